@@ -0,0 +1,210 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+#[cfg(unix)] use std::os::unix::net::UnixStream;
+#[cfg(unix)] use std::path::PathBuf;
+
+use serde_json::{self, json, Value};
+
+use status::{GPUData, Status, STATUS_VERSION};
+
+/// Where a running instance accepts control connections
+pub enum Endpoint {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// A connection to a running instance speaking the control protocol
+pub struct Connection {
+    reader: Box<dyn BufRead>,
+    writer: Box<dyn Write>,
+}
+
+#[derive(Deserialize)]
+struct Notification {
+    params: Status,
+}
+
+fn check_version(status: &Status) -> Result<(), String> {
+    if status.version != STATUS_VERSION {
+        return Err(format!("Unsupported status version {}; expected {}",
+                           status.version, STATUS_VERSION));
+    }
+    Ok(())
+}
+
+impl Connection {
+
+    pub fn connect(endpoint: &Endpoint) -> Result<Connection, String> {
+        match *endpoint {
+            Endpoint::Tcp(ref addr) => {
+                let stream = TcpStream::connect(addr.as_str())
+                    .map_err(|e| format!("Could not connect to {}: {}", addr, e))?;
+                let reader = stream.try_clone().map_err(|e| e.to_string())?;
+                Ok(Connection {
+                    reader: Box::new(BufReader::new(reader)),
+                    writer: Box::new(stream),
+                })
+            },
+            #[cfg(unix)]
+            Endpoint::Unix(ref path) => {
+                let stream = UnixStream::connect(path)
+                    .map_err(|e| format!("Could not connect to {:?}: {}", path, e))?;
+                let reader = stream.try_clone().map_err(|e| e.to_string())?;
+                Ok(Connection {
+                    reader: Box::new(BufReader::new(reader)),
+                    writer: Box::new(stream),
+                })
+            },
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => Ok(Some(line)),
+            Err(e) => Err(format!("Could not read from daemon: {}", e)),
+        }
+    }
+
+    /// Sends a request for `method` and returns its result
+    pub fn call(&mut self, method: &str) -> Result<Value, String> {
+        let req = json!({"id": 1, "method": method});
+        writeln!(self.writer, "{}", req)
+            .map_err(|e| format!("Could not send request: {}", e))?;
+
+        let line = self.read_line()?
+            .ok_or_else(|| "Connection closed by daemon".to_string())?;
+        let mut response: Value = serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid response: {}", e))?;
+
+        if let Some(error) = response.get("error") {
+            return Err(format!("{} ({})", error["message"].as_str().unwrap_or("unknown error"),
+                               error["code"]));
+        }
+        Ok(response["result"].take())
+    }
+
+    pub fn get_status(&mut self) -> Result<Status, String> {
+        let status: Status = serde_json::from_value(self.call("get_status")?)
+            .map_err(|e| format!("Invalid status: {}", e))?;
+        check_version(&status)?;
+        Ok(status)
+    }
+
+    /// Switches the connection to receive an update after every tick
+    pub fn subscribe(&mut self) -> Result<(), String> {
+        self.call("subscribe").map(|_| ())
+    }
+
+    /// Waits for the next update of a subscribed connection; `None` once the
+    /// daemon has closed the connection
+    pub fn next_status(&mut self) -> Result<Option<Status>, String> {
+        let line = match self.read_line()? {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        let notification: Notification = serde_json::from_str(&line)
+            .map_err(|e| format!("Invalid status update: {}", e))?;
+        check_version(&notification.params)?;
+        Ok(Some(notification.params))
+    }
+}
+
+fn format_row(gpu: &GPUData) -> Vec<String> {
+    let join = |values: Vec<String>| if values.is_empty() { "-".to_string() } else { values.join(",") };
+
+    vec![
+        format!("{}{}", if gpu.controlled { "*" } else { " " }, gpu.index),
+        gpu.name.clone(),
+        format!("{}°C", gpu.temp),
+        gpu.throttle_distance.map(|d| format!("{}°C", d)).unwrap_or_else(|| "-".to_string()),
+        gpu.target.map(|t| format!("{}%", t)).unwrap_or_else(|| "-".to_string()),
+        join(gpu.coolers.iter().map(|c| format!("{}%", c.speed)).collect()),
+        join(gpu.coolers.iter().map(|c| c.rpm.to_string()).collect()),
+        gpu.mode.map(|m| format!("{:?}", m)).unwrap_or_else(|| "-".to_string()),
+        gpu.profile.clone().unwrap_or_else(|| "-".to_string()),
+        join(gpu.coolers.iter()
+            .filter_map(|c| c.fault.map(|f| format!("{}:{}", c.id, f.name())))
+            .collect()),
+        gpu.conflict.as_ref().map(|c| match c.resume_in {
+            Some(s) => format!("{}:{} {}s", c.kind.name(), c.policy, s),
+            None => format!("{}:{}", c.kind.name(), c.policy),
+        }).unwrap_or_else(|| "-".to_string()),
+    ]
+}
+
+/// Renders `status` as a table with one line per GPU. The controlled GPU is
+/// marked with `*`.
+pub fn format_table(status: &Status) -> String {
+    let header = ["GPU", "NAME", "TEMP", "HEADROOM", "TARGET", "SPEED", "RPM", "MODE", "PROFILE", "FAULTS",
+                  "CONFLICT"];
+    let mut rows = vec![header.iter().map(|h| h.to_string()).collect::<Vec<String>>()];
+    rows.extend(status.gpus.iter().map(format_row));
+
+    let mut widths = vec![0; header.len()];
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    for row in &rows {
+        let cells: Vec<String> = row.iter().zip(widths.iter())
+            .map(|(cell, w)| format!("{}{}", cell, " ".repeat(w - cell.chars().count())))
+            .collect();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[test]
+fn test_format_table() {
+    use status::make_test_status;
+
+    assert_eq!(format_table(&make_test_status()),
+        "GPU  NAME                TEMP  HEADROOM  TARGET  SPEED    RPM        MODE    PROFILE  FAULTS     CONFLICT\n \
+         0   Other GPU           35°C  -         -       0%       0          Auto    -        -          -\n\
+         *1   GeForce \"Test\" GPU  55°C  33°C      45%     40%,41%  1200,1250  Manual  silent   3:low_rpm  level:yield 240s\n");
+}
+
+#[test]
+fn test_client_over_loopback() {
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use server::{self, Command, Subscribers};
+    use status::make_test_status;
+
+    static TEST_RUNNING: AtomicBool = AtomicBool::new(true);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel::<server::Request>();
+    let subs = Arc::new(Subscribers::new());
+
+    let srv_subs = subs.clone();
+    thread::spawn(move || {
+        server::serve_tcp(listener, tx, srv_subs, &TEST_RUNNING, server::Access::ReadOnly)
+    });
+    thread::spawn(move || {
+        for req in rx {
+            assert_eq!(req.command, Command::GetStatus);
+            req.reply.send(Ok(serde_json::to_value(make_test_status()).unwrap())).unwrap();
+        }
+    });
+
+    let endpoint = Endpoint::Tcp(addr.to_string());
+    let mut conn = Connection::connect(&endpoint).unwrap();
+    assert_eq!(conn.get_status().unwrap(), make_test_status());
+
+    conn.subscribe().unwrap();
+    subs.publish(&make_test_status());
+    assert_eq!(conn.next_status().unwrap(), Some(make_test_status()));
+    subs.close();
+    assert_eq!(conn.next_status().unwrap(), None);
+}
@@ -2,10 +2,14 @@ use std::fs;
 use std::io::prelude::*;
 use std::path::PathBuf;
 
+use fanspeedcurve::FanspeedCurve;
+
 pub trait Curve {
     fn points(&self, id: usize) -> &Vec<(u16, u16)>;
     fn enabled(&self, id: usize) -> bool;
     fn fanflicker(&self, id: usize) -> Option<(u16, u16)>;
+    fn profile(&self, id: usize) -> Option<&str>;
+    fn profiles(&self) -> &[ProfileConf];
 }
 
 #[derive(Debug, Deserialize)]
@@ -14,27 +18,242 @@ pub enum Config {
     Legacy(GpuConfig<LegacyConf>),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct GpuConfig<T> {
     #[serde(rename = "gpu")]
-    gpus: Vec<T>,
+    pub gpus: Vec<T>,
+    #[serde(rename = "profile", default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<ProfileConf>,
+    #[serde(rename = "hook", default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<HookConf>,
 }
 
 fn true_() -> bool { true }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TomlConf {
     #[serde(default)]
-    id: u32,
+    pub id: u32,
     #[serde(default = "true_")]
-    enabled: bool,
-    points: Vec<(u16, u16)>,
-    fanflicker: Option<(u16, u16)>,
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub points: Vec<(u16, u16)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fanflicker: Option<(u16, u16)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// How the curve is applied when the fan is already spinning on auto
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    /// Leave the fan alone if it is already spinning on auto
+    #[default]
+    Yield,
+    /// Always apply the curve (same as `-f`)
+    Force,
+}
+
+/// A named set of curve, limits, fanflicker range and strategy that can be
+/// switched at runtime
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfileConf {
+    pub name: String,
+    pub points: Vec<(u16, u16)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<(u16, u16)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fanflicker: Option<(u16, u16)>,
+    #[serde(default)]
+    pub strategy: Strategy,
+}
+
+/// Name of the profile made of the points of the GPU section itself
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Condition that runs a hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// The temperature rises above the threshold
+    TempAbove,
+    /// The temperature drops below the threshold
+    TempBelow,
+    /// The fan control mode changes, e.g. back to auto after errors
+    ModeChanged,
+    /// A cooler reports no RPM although a speed is set
+    FanStalled,
+    /// The fanflicker fix changes the requested speed
+    Fanflicker,
+    /// The fan speed could not be updated
+    UpdateError,
+    /// Another profile is activated
+    ProfileChanged,
+}
+
+fn default_min_interval() -> u64 { 60 }
+
+/// A command that is run when `event` occurs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HookConf {
+    pub event: HookEvent,
+    /// Program and arguments; not passed through a shell
+    pub command: Vec<String>,
+    /// Temperature of `temp_above` and `temp_below`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<u16>,
+    /// Seconds a temperature or stall condition must persist before the
+    /// hook runs
+    #[serde(default)]
+    pub debounce: u64,
+    /// Minimum seconds between two runs of the hook
+    #[serde(default = "default_min_interval")]
+    pub min_interval: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LegacyConf {
-    points: Vec<(u16, u16)>,
+    pub points: Vec<(u16, u16)>,
+}
+
+impl GpuConfig<TomlConf> {
+
+    /// Creates an empty configuration
+    pub fn new() -> GpuConfig<TomlConf> {
+        GpuConfig { gpus: Vec::new(), profiles: Vec::new(), hooks: Vec::new() }
+    }
+
+    pub fn with_gpu(mut self, gpu: TomlConf) -> GpuConfig<TomlConf> {
+        self.gpus.push(gpu);
+        self
+    }
+
+    pub fn with_profile(mut self, profile: ProfileConf) -> GpuConfig<TomlConf> {
+        self.profiles.push(profile);
+        self
+    }
+
+    pub fn with_hook(mut self, hook: HookConf) -> GpuConfig<TomlConf> {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// Returns the section of GPU `id`, if any
+    pub fn gpu_mut(&mut self, id: u32) -> Option<&mut TomlConf> {
+        self.gpus.iter_mut().find(|g| g.id == id)
+    }
+
+    /// Returns the profile named `name`, if any
+    pub fn profile_mut(&mut self, name: &str) -> Option<&mut ProfileConf> {
+        self.profiles.iter_mut().find(|p| p.name == name)
+    }
+}
+
+impl Default for GpuConfig<TomlConf> {
+    fn default() -> GpuConfig<TomlConf> { GpuConfig::new() }
+}
+
+impl TomlConf {
+
+    /// Creates an enabled GPU section without a curve
+    pub fn new(id: u32) -> TomlConf {
+        TomlConf { id, enabled: true, points: Vec::new(), fanflicker: None, profile: None }
+    }
+
+    pub fn with_enabled(mut self, enabled: bool) -> TomlConf {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn with_points(mut self, points: Vec<(u16, u16)>) -> TomlConf {
+        self.points = points;
+        self
+    }
+
+    pub fn with_fanflicker(mut self, range: (u16, u16)) -> TomlConf {
+        self.fanflicker = Some(range);
+        self
+    }
+
+    pub fn with_profile(mut self, name: &str) -> TomlConf {
+        self.profile = Some(name.to_string());
+        self
+    }
+}
+
+impl ProfileConf {
+
+    pub fn new(name: &str, points: Vec<(u16, u16)>) -> ProfileConf {
+        ProfileConf {
+            name: name.to_string(),
+            points,
+            limits: None,
+            fanflicker: None,
+            strategy: Strategy::default(),
+        }
+    }
+
+    pub fn with_limits(mut self, limits: (u16, u16)) -> ProfileConf {
+        self.limits = Some(limits);
+        self
+    }
+
+    pub fn with_fanflicker(mut self, range: (u16, u16)) -> ProfileConf {
+        self.fanflicker = Some(range);
+        self
+    }
+
+    pub fn with_strategy(mut self, strategy: Strategy) -> ProfileConf {
+        self.strategy = strategy;
+        self
+    }
+}
+
+impl HookConf {
+
+    pub fn new(event: HookEvent, command: Vec<String>) -> HookConf {
+        HookConf {
+            event,
+            command,
+            threshold: None,
+            debounce: 0,
+            min_interval: default_min_interval(),
+        }
+    }
+
+    pub fn with_threshold(mut self, threshold: u16) -> HookConf {
+        self.threshold = Some(threshold);
+        self
+    }
+
+    pub fn with_debounce(mut self, seconds: u64) -> HookConf {
+        self.debounce = seconds;
+        self
+    }
+
+    pub fn with_min_interval(mut self, seconds: u64) -> HookConf {
+        self.min_interval = seconds;
+        self
+    }
+}
+
+impl Config {
+
+    /// Returns the configuration in the TOML model; legacy configurations
+    /// are converted to a single GPU section
+    pub fn into_toml(self) -> GpuConfig<TomlConf> {
+        match self {
+            Config::Toml(conf) => conf,
+            Config::Legacy(conf) => GpuConfig {
+                gpus: conf.gpus.into_iter()
+                    .map(|g| TomlConf::new(0).with_points(g.points))
+                    .collect(),
+                profiles: conf.profiles,
+                hooks: conf.hooks,
+            }
+        }
+    }
 }
 
 impl Curve for Config {
@@ -58,11 +277,72 @@ impl Curve for Config {
             Config::Legacy(_) => None,
         }
     }
+
+    fn profile(&self, id: usize) -> Option<&str> {
+        match self {
+            Config::Toml(conf) => conf.gpus[id].profile.as_deref(),
+            Config::Legacy(_) => None,
+        }
+    }
+
+    fn profiles(&self) -> &[ProfileConf] {
+        match self {
+            Config::Toml(conf) => &conf.profiles,
+            Config::Legacy(conf) => &conf.profiles,
+        }
+    }
+}
+
+fn validate_toml(conf: &GpuConfig<TomlConf>) -> Result<(), String> {
+    for (i, p) in conf.profiles.iter().enumerate() {
+        if p.name == DEFAULT_PROFILE {
+            return Err(format!("profile name \"{}\" is reserved for the curve of the GPU",
+                               p.name));
+        }
+        if conf.profiles[..i].iter().any(|q| q.name == p.name) {
+            return Err(format!("duplicate profile \"{}\"", p.name));
+        }
+    }
+
+    for gpu in &conf.gpus {
+        match gpu.profile {
+            Some(ref name) => {
+                if !conf.profiles.iter().any(|p| &p.name == name) {
+                    return Err(format!("GPU {} uses undefined profile \"{}\"",
+                                       gpu.id, name));
+                }
+            },
+            None => {
+                if gpu.points.is_empty() {
+                    return Err(format!("GPU {} has neither points nor a profile", gpu.id));
+                }
+            }
+        }
+    }
+
+    for (i, h) in conf.hooks.iter().enumerate() {
+        if h.command.is_empty() || h.command[0].is_empty() {
+            return Err(format!("hook {} has no command", i + 1));
+        }
+        match (h.event, h.threshold) {
+            (HookEvent::TempAbove, None) | (HookEvent::TempBelow, None) =>
+                return Err(format!("hook {} requires a threshold", i + 1)),
+            (HookEvent::TempAbove, Some(_)) | (HookEvent::TempBelow, Some(_)) => {},
+            (_, Some(_)) =>
+                return Err(format!("hook {} does not take a threshold", i + 1)),
+            (_, None) => {},
+        }
+    }
+
+    Ok(())
 }
 
 pub fn from_string(conf: &str) -> Result<Config, String> {
     match toml::from_str::<GpuConfig<TomlConf>>(conf) {
-        Ok(c) => Ok(Config::Toml(c)),
+        Ok(c) => {
+            validate_toml(&c).map_err(|e| format!("config validation failed: {}", e))?;
+            Ok(Config::Toml(c))
+        },
         Err(e) => {
             // Toml parsing failed; try legacy config instead
             if might_be_legacy_string(conf) {
@@ -74,6 +354,29 @@ pub fn from_string(conf: &str) -> Result<Config, String> {
     }
 }
 
+/// Serializes `conf` to TOML. The configuration is validated first, including
+/// the curves, so the output is always accepted by `from_string`.
+pub fn to_string(conf: &GpuConfig<TomlConf>) -> Result<String, String> {
+    validate_toml(conf).map_err(|e| format!("config validation failed: {}", e))?;
+
+    for gpu in conf.gpus.iter().filter(|g| !g.points.is_empty()) {
+        FanspeedCurve::new(gpu.points.clone())
+            .map_err(|e| format!("config validation failed: GPU {}: {}", gpu.id, e))?;
+    }
+
+    for p in &conf.profiles {
+        FanspeedCurve::new(p.points.clone())
+            .map_err(|e| format!("config validation failed: profile \"{}\": {}", p.name, e))?;
+    }
+
+    toml::to_string(conf).map_err(|e| format!("config serialization failed: {}", e))
+}
+
+pub fn to_file(conf: &GpuConfig<TomlConf>, path: PathBuf) -> Result<(), String> {
+    let contents = to_string(conf)?;
+    fs::write(&path, contents).map_err(|e| format!("Could not write file: {}", e))
+}
+
 #[test]
 fn test_valid_toml_from_string() {
     let cfg = from_string(&"[[gpu]]
@@ -156,6 +459,480 @@ fn test_invalid_legacy_from_string() {
     }
 }
 
+#[test]
+fn test_profiles_from_string() {
+    let cfg = from_string(&"[[gpu]]
+                            id = 0
+                            profile = \"balanced\"
+
+                            [[profile]]
+                            name = \"silent\"
+                            points = [[50, 20], [80, 60]]
+                            limits = [0, 60]
+
+                            [[profile]]
+                            name = \"balanced\"
+                            points = [[40, 30], [80, 80]]
+                            fanflicker = [11, 38]
+                            strategy = \"force\"");
+
+    assert!(cfg.is_ok());
+
+    let cfg = cfg.unwrap();
+
+    assert_eq!(cfg.profile(0), Some("balanced"));
+    assert!(cfg.points(0).is_empty());
+
+    let profiles = cfg.profiles();
+    assert_eq!(profiles.len(), 2);
+    assert_eq!(profiles[0].name, "silent");
+    assert_eq!(profiles[0].limits, Some((0, 60)));
+    assert_eq!(profiles[0].strategy, Strategy::Yield);
+    assert_eq!(profiles[1].points, vec![(40, 30), (80, 80)]);
+    assert_eq!(profiles[1].fanflicker, Some((11, 38)));
+    assert_eq!(profiles[1].strategy, Strategy::Force);
+}
+
+#[test]
+fn test_undefined_profile_from_string() {
+    let cfg = from_string(&"[[gpu]]\nprofile = \"turbo\"");
+
+    assert!(cfg.is_err());
+
+    if let Err(msg) = cfg {
+        assert!(msg.find("undefined profile \"turbo\"").is_some());
+    }
+}
+
+#[test]
+fn test_reserved_profile_from_string() {
+    let cfg = from_string(&"[[gpu]]
+                            points = [[40, 30], [80, 80]]
+
+                            [[profile]]
+                            name = \"default\"
+                            points = [[50, 20], [80, 60]]");
+
+    assert!(cfg.is_err());
+
+    if let Err(msg) = cfg {
+        assert!(msg.find("\"default\" is reserved").is_some());
+    }
+}
+
+#[test]
+fn test_no_points_from_string() {
+    let cfg = from_string(&"[[gpu]]\nid = 0\nenabled = true");
+
+    assert!(cfg.is_err());
+}
+
+#[test]
+fn test_hooks_from_string() {
+    let conf = from_string("[[gpu]]
+                            points = [[40, 20], [80, 80]]
+
+                            [[hook]]
+                            event = \"temp_above\"
+                            threshold = 85
+                            debounce = 10
+                            command = [\"notify-send\", \"GPU is hot\"]
+
+                            [[hook]]
+                            event = \"mode_changed\"
+                            min_interval = 0
+                            command = [\"/usr/local/bin/fan-alert\"]").unwrap().into_toml();
+
+    assert_eq!(conf.hooks, vec![
+        HookConf::new(HookEvent::TempAbove,
+                      vec!["notify-send".to_string(), "GPU is hot".to_string()])
+            .with_threshold(85).with_debounce(10),
+        HookConf::new(HookEvent::ModeChanged, vec!["/usr/local/bin/fan-alert".to_string()])
+            .with_min_interval(0),
+    ]);
+    assert_eq!(conf.hooks[0].min_interval, 60);
+    assert_eq!(from_string(&to_string(&conf).unwrap()).unwrap().into_toml(), conf);
+}
+
+#[test]
+fn test_invalid_hooks_from_string() {
+    let errors = [
+        ("event = \"temp_above\"\ncommand = [\"true\"]", "hook 1 requires a threshold"),
+        ("event = \"update_error\"\nthreshold = 80\ncommand = [\"true\"]",
+         "hook 1 does not take a threshold"),
+        ("event = \"fan_stalled\"\ncommand = []", "hook 1 has no command"),
+    ];
+
+    for &(hook, msg) in errors.iter() {
+        let cfg = from_string(&format!("[[gpu]]\npoints = [[40, 20], [80, 80]]\n[[hook]]\n{}", hook));
+        assert_eq!(cfg.unwrap_err(), format!("config validation failed: {}", msg));
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let cfg = from_string("[[gpu]]
+                           id = 0
+                           points = [[40, 20], [60, 50], [80, 80]]
+                           fanflicker = [11, 38]
+
+                           [[gpu]]
+                           id = 1
+                           profile = \"silent\"
+
+                           [[profile]]
+                           name = \"silent\"
+                           points = [[50, 20], [80, 60]]
+                           limits = [0, 60]").unwrap();
+
+    let mut conf = cfg.into_toml();
+    conf.gpu_mut(0).unwrap().points[1] = (60, 45);
+
+    let written = to_string(&conf).unwrap();
+    let reread = from_string(&written).unwrap().into_toml();
+
+    assert_eq!(reread, conf);
+    assert_eq!(reread.gpus[0].points, vec![(40, 20), (60, 45), (80, 80)]);
+}
+
+#[test]
+fn test_builder_to_string() {
+    let conf = GpuConfig::new()
+        .with_gpu(TomlConf::new(0).with_profile("performance"))
+        .with_profile(ProfileConf::new("performance", vec![(30, 40), (70, 100)])
+                      .with_strategy(Strategy::Force));
+
+    let written = to_string(&conf).unwrap();
+
+    assert!(written.contains("strategy = \"force\""));
+    assert_eq!(from_string(&written).unwrap().into_toml(), conf);
+}
+
+#[test]
+fn test_invalid_curve_to_string() {
+    let conf = GpuConfig::new()
+        .with_gpu(TomlConf::new(0).with_points(vec![(40, 60), (80, 20)]));
+
+    assert!(to_string(&conf).is_err());
+}
+
+#[test]
+fn test_legacy_into_toml() {
+    let conf = from_string("30 20\n80 80").unwrap().into_toml();
+
+    assert_eq!(conf.gpus.len(), 1);
+    assert_eq!(conf.gpus[0].points, vec![(30, 20), (80, 80)]);
+    assert!(to_string(&conf).is_ok());
+}
+
+#[test]
+fn test_schema_matches_model() {
+    use std::collections::BTreeSet;
+
+    let schema: serde_json::Value =
+        serde_json::from_str(include_str!("../nvfancontrol.schema.json")).unwrap();
+
+    let keys = |v: &serde_json::Value| -> BTreeSet<String> {
+        v.as_object().unwrap().keys().cloned().collect()
+    };
+
+    let gpu = TomlConf::new(0).with_points(vec![(1, 2)]).with_fanflicker((1, 2))
+        .with_profile("p");
+    let profile = ProfileConf::new("p", vec![(1, 2)]).with_limits((1, 2))
+        .with_fanflicker((1, 2));
+    let hook = HookConf::new(HookEvent::TempAbove, vec!["true".to_string()])
+        .with_threshold(1);
+
+    assert_eq!(keys(&schema["definitions"]["gpu"]["properties"]),
+               keys(&serde_json::to_value(&gpu).unwrap()));
+    assert_eq!(keys(&schema["definitions"]["profile"]["properties"]),
+               keys(&serde_json::to_value(&profile).unwrap()));
+    assert_eq!(keys(&schema["definitions"]["hook"]["properties"]),
+               keys(&serde_json::to_value(&hook).unwrap()));
+    assert_eq!(keys(&schema["properties"]),
+               keys(&serde_json::to_value(GpuConfig::new().with_gpu(gpu)
+                                          .with_profile(profile)
+                                          .with_hook(hook)).unwrap()));
+}
+
+/// Prefix of the environment variables that override configuration keys
+pub const ENV_PREFIX: &str = "NVFANCONTROL_";
+
+/// Global settings taken from `NVFANCONTROL_*` environment variables. These
+/// have no equivalent in the configuration file and are applied on top of
+/// the defaults but below the command line.
+#[derive(Debug, Default, PartialEq)]
+pub struct EnvSettings {
+    /// `NVFANCONTROL_LIMITS`; `Some(None)` disables the limits
+    pub limits: Option<Option<(u16, u16)>>,
+    /// `NVFANCONTROL_PORT`; enables the TCP server
+    pub port: Option<u32>,
+    /// `NVFANCONTROL_PROFILE`; profile to activate on startup
+    pub profile: Option<String>,
+}
+
+/// Parses a curve given as comma separated `TEMP:SPEED` pairs, for instance
+/// `40:20,60:50,80:80`
+pub fn parse_points(s: &str) -> Result<Vec<(u16, u16)>, String> {
+    s.split(',')
+        .map(|p| p.trim())
+        .map(|p| {
+            let parts: Vec<&str> = p.split(':').map(|v| v.trim()).collect();
+            if parts.len() != 2 {
+                return Err(format!("invalid point \"{}\"; expected TEMP:SPEED", p));
+            }
+            match (parts[0].parse::<u16>(), parts[1].parse::<u16>()) {
+                (Ok(x), Ok(y)) => Ok((x, y)),
+                (Err(e), _) => Err(format!("invalid temperature \"{}\": {}", parts[0], e)),
+                (_, Err(e)) => Err(format!("invalid speed \"{}\": {}", parts[1], e)),
+            }
+        })
+        .collect()
+}
+
+/// GPU id, if given, and points of an inline curve definition
+pub type CurveArg = (Option<u32>, Vec<(u16, u16)>);
+
+/// Parses an inline curve definition of the form `[GPU=]TEMP:SPEED,...`.
+/// Returns the GPU id, if given, along with the points which are checked
+/// the same way as those of the configuration file.
+pub fn parse_curve(s: &str) -> Result<CurveArg, String> {
+    let (gpu, points) = match s.find('=') {
+        Some(idx) => {
+            let id = s[..idx].trim();
+            match id.parse::<u32>() {
+                Ok(id) => (Some(id), &s[idx+1..]),
+                Err(e) => return Err(format!("invalid GPU id \"{}\": {}", id, e)),
+            }
+        },
+        None => (None, s)
+    };
+
+    let points = parse_points(points)?;
+    FanspeedCurve::new(points.clone())?;
+
+    Ok((gpu, points))
+}
+
+fn parse_range(s: &str) -> Result<(u16, u16), String> {
+    let parts: Vec<&str> = s.split(',').map(|v| v.trim()).collect();
+    if parts.len() != 2 {
+        return Err(format!("invalid range \"{}\"; expected LOWER,UPPER", s));
+    }
+    match (parts[0].parse::<u16>(), parts[1].parse::<u16>()) {
+        (Ok(low), Ok(high)) if low > high =>
+            Err(format!("lower limit {} is greater than upper limit {}", low, high)),
+        (Ok(low), Ok(high)) => Ok((low, high)),
+        (Err(e), _) => Err(format!("invalid lower limit \"{}\": {}", parts[0], e)),
+        (_, Err(e)) => Err(format!("invalid upper limit \"{}\": {}", parts[1], e)),
+    }
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s.trim().to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("invalid boolean \"{}\"", s)),
+    }
+}
+
+/// Applies the `NVFANCONTROL_*` variables found in `vars` to `conf`.
+///
+/// Per GPU keys are named `NVFANCONTROL_GPU<ID>_<KEY>` with `KEY` one of
+/// `POINTS`, `FANFLICKER`, `ENABLED` or `PROFILE` and replace the respective
+/// key of the `[[gpu]]` section with that id, which is created if missing.
+/// Like `--curve`, `POINTS` also drops the profile of the section unless
+/// `PROFILE` is given for the same GPU. The remaining variables (`LIMITS`,
+/// `PORT` and `PROFILE`) are returned as `EnvSettings`. Precedence is command
+/// line, environment, configuration file and then the builtin defaults.
+pub fn apply_env<I>(conf: &mut GpuConfig<TomlConf>, vars: I) -> Result<EnvSettings, String>
+    where I: IntoIterator<Item=(String, String)>
+{
+    let mut settings = EnvSettings::default();
+    // GPUs given points or a profile; applied last as the order of the
+    // variables is arbitrary
+    let mut points = Vec::new();
+    let mut profiles = Vec::new();
+
+    for (key, val) in vars {
+        let name = match key.strip_prefix(ENV_PREFIX) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let err = |e: String| format!("{}: {}", key, e);
+
+        match name {
+            "LIMITS" => {
+                settings.limits = if val.trim() == "0" {
+                    Some(None)
+                } else {
+                    Some(Some(parse_range(&val).map_err(err)?))
+                };
+            },
+            "PORT" => {
+                match val.trim().parse::<u16>() {
+                    Ok(port) => settings.port = Some(port as u32),
+                    Err(e) => return Err(err(format!("invalid port \"{}\": {}", val, e))),
+                }
+            },
+            "PROFILE" => settings.profile = Some(val.trim().to_string()),
+            _ if name.starts_with("GPU") => {
+                let mut split = name[3..].splitn(2, '_');
+                let id = match split.next().map(|id| id.parse::<u32>()) {
+                    Some(Ok(id)) => id,
+                    _ => return Err(err("invalid GPU id".to_string())),
+                };
+
+                if conf.gpu_mut(id).is_none() {
+                    conf.gpus.push(TomlConf::new(id));
+                }
+                let gpu = conf.gpu_mut(id).unwrap();
+
+                match split.next() {
+                    Some("POINTS") => {
+                        gpu.points = parse_points(&val).map_err(err)?;
+                        points.push(id);
+                    },
+                    Some("FANFLICKER") => gpu.fanflicker = Some(parse_range(&val).map_err(err)?),
+                    Some("ENABLED") => gpu.enabled = parse_bool(&val).map_err(err)?,
+                    Some("PROFILE") => {
+                        gpu.profile = Some(val.trim().to_string());
+                        profiles.push(id);
+                    },
+                    _ => return Err(err("unknown GPU setting".to_string())),
+                }
+            },
+            _ => {
+                warn!("Ignoring unknown environment variable {}", key);
+                continue;
+            }
+        }
+
+        debug!("Configuration override from environment: {}={}", key, val);
+    }
+
+    // An explicit curve takes precedence over the configured profile
+    for id in points.iter().filter(|id| !profiles.contains(id)) {
+        if let Some(gpu) = conf.gpu_mut(*id) {
+            gpu.profile = None;
+        }
+    }
+
+    validate_toml(conf).map_err(|e| format!("config validation failed: {}", e))?;
+
+    Ok(settings)
+}
+
+#[cfg(test)]
+fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn test_parse_points() {
+    assert_eq!(parse_points("40:20,60:50, 80 : 80"), Ok(vec![(40, 20), (60, 50), (80, 80)]));
+    assert_eq!(parse_points("40:20,60"),
+               Err("invalid point \"60\"; expected TEMP:SPEED".to_string()));
+    assert!(parse_points("40:20,x:50").unwrap_err().starts_with("invalid temperature \"x\""));
+    assert!(parse_points("40:20,60:-5").unwrap_err().starts_with("invalid speed \"-5\""));
+    assert!(parse_points("").is_err());
+}
+
+#[test]
+fn test_parse_curve() {
+    assert_eq!(parse_curve("40:20,55:35,70:60,80:80"),
+               Ok((None, vec![(40, 20), (55, 35), (70, 60), (80, 80)])));
+    assert_eq!(parse_curve("1=40:20,80:80"), Ok((Some(1), vec![(40, 20), (80, 80)])));
+    assert_eq!(parse_curve("40:20"), Err("not enough data points".to_string()));
+    assert_eq!(parse_curve("40:60,80:20"), Err("not monotonically increasing".to_string()));
+    assert!(parse_curve("a=40:20,80:80").unwrap_err().starts_with("invalid GPU id \"a\""));
+}
+
+#[test]
+fn test_apply_env() {
+    let mut conf = GpuConfig::new()
+        .with_gpu(TomlConf::new(0).with_points(vec![(30, 20), (80, 80)]));
+
+    let settings = apply_env(&mut conf, env(&[
+        ("HOME", "/root"),
+        ("NVFANCONTROL_GPU0_POINTS", "40:20,60:50,80:80"),
+        ("NVFANCONTROL_GPU1_POINTS", "50:30,90:100"),
+        ("NVFANCONTROL_GPU1_FANFLICKER", "11,38"),
+        ("NVFANCONTROL_GPU1_ENABLED", "no"),
+        ("NVFANCONTROL_LIMITS", "10,90"),
+        ("NVFANCONTROL_PORT", "12345"),
+        ("NVFANCONTROL_PROFILE", "silent"),
+    ])).unwrap();
+
+    assert_eq!(conf.gpus[0].points, vec![(40, 20), (60, 50), (80, 80)]);
+    assert_eq!(conf.gpus[1].id, 1);
+    assert_eq!(conf.gpus[1].points, vec![(50, 30), (90, 100)]);
+    assert_eq!(conf.gpus[1].fanflicker, Some((11, 38)));
+    assert!(!conf.gpus[1].enabled);
+    assert_eq!(settings, EnvSettings {
+        limits: Some(Some((10, 90))),
+        port: Some(12345),
+        profile: Some("silent".to_string()),
+    });
+
+    let settings = apply_env(&mut conf, env(&[("NVFANCONTROL_LIMITS", "0")])).unwrap();
+    assert_eq!(settings.limits, Some(None));
+}
+
+#[test]
+fn test_apply_env_points_over_profile() {
+    let profiled = || GpuConfig::new()
+        .with_gpu(TomlConf::new(0).with_points(vec![(30, 20), (80, 80)]).with_profile("quiet"))
+        .with_gpu(TomlConf::new(1).with_points(vec![(30, 20), (80, 80)]).with_profile("quiet"))
+        .with_profile(ProfileConf::new("quiet", vec![(50, 20), (90, 60)]))
+        .with_profile(ProfileConf::new("loud", vec![(30, 50), (70, 100)]));
+
+    // The curve from the environment is used instead of the profile
+    let mut conf = profiled();
+    apply_env(&mut conf, env(&[("NVFANCONTROL_GPU0_POINTS", "40:20,80:80")])).unwrap();
+    assert_eq!(conf.gpus[0].points, vec![(40, 20), (80, 80)]);
+    assert_eq!(conf.gpus[0].profile, None);
+    assert_eq!(conf.gpus[1].profile, Some("quiet".to_string()));
+
+    // Unless a profile is given in the environment as well, in either order
+    let points = ("NVFANCONTROL_GPU0_POINTS", "40:20,80:80");
+    let profile = ("NVFANCONTROL_GPU0_PROFILE", "loud");
+    for vars in [[points, profile], [profile, points]].iter() {
+        let mut conf = profiled();
+        apply_env(&mut conf, env(vars)).unwrap();
+        assert_eq!(conf.gpus[0].profile, Some("loud".to_string()));
+    }
+}
+
+#[test]
+fn test_apply_env_errors() {
+    let errors = [
+        (("NVFANCONTROL_GPU0_POINTS", "40-20"),
+         "NVFANCONTROL_GPU0_POINTS: invalid point \"40-20\"; expected TEMP:SPEED"),
+        (("NVFANCONTROL_GPUX_POINTS", "40:20"), "NVFANCONTROL_GPUX_POINTS: invalid GPU id"),
+        (("NVFANCONTROL_GPU0_SPEED", "40"), "NVFANCONTROL_GPU0_SPEED: unknown GPU setting"),
+        (("NVFANCONTROL_GPU0_ENABLED", "maybe"),
+         "NVFANCONTROL_GPU0_ENABLED: invalid boolean \"maybe\""),
+        (("NVFANCONTROL_LIMITS", "80,20"),
+         "NVFANCONTROL_LIMITS: lower limit 80 is greater than upper limit 20"),
+        (("NVFANCONTROL_LIMITS", "20"),
+         "NVFANCONTROL_LIMITS: invalid range \"20\"; expected LOWER,UPPER"),
+        (("NVFANCONTROL_GPU0_PROFILE", "turbo"),
+         "config validation failed: GPU 0 uses undefined profile \"turbo\""),
+    ];
+
+    for &((key, val), msg) in errors.iter() {
+        let mut conf = GpuConfig::new()
+            .with_gpu(TomlConf::new(0).with_points(vec![(30, 20), (80, 80)]));
+        assert_eq!(apply_env(&mut conf, env(&[(key, val)])), Err(msg.to_string()));
+    }
+
+    let mut conf = GpuConfig::new();
+    let err = apply_env(&mut conf, env(&[("NVFANCONTROL_PORT", "70000")])).unwrap_err();
+    assert!(err.starts_with("NVFANCONTROL_PORT: invalid port \"70000\""));
+}
+
 pub fn from_file(path: PathBuf) -> Result<Config, String> {
     match fs::File::open(path.to_str().unwrap()) {
         Ok(mut file) => {
@@ -180,48 +957,135 @@ fn might_be_legacy_string(conf: &str) -> bool {
     true
 }
 
-fn from_legacy_string(conf: &str) -> Result<Config, String> {
-    let mut curve: Vec<(u16, u16)>;
+/// A single line of a legacy configuration file
+#[derive(Debug, PartialEq)]
+enum LegacyLine<'a> {
+    Blank,
+    Comment(&'a str),
+    Point(u16, u16),
+    Invalid(String),
+}
 
-    curve = Vec::new();
+fn parse_legacy_line(line: &str) -> LegacyLine<'_> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        return LegacyLine::Blank;
+    }
+    if trimmed.starts_with('#') {
+        return LegacyLine::Comment(trimmed);
+    }
 
-    for line in conf.lines() {
-        //let line = raw_line.unwrap();
-        let trimmed = line.trim();
-        if trimmed.starts_with('#') {
-            continue;
-        }
+    let parts = trimmed.split_whitespace().collect::<Vec<&str>>();
 
-        let parts = trimmed.split_whitespace().collect::<Vec<&str>>();
+    if parts.len() != 2 {
+        return LegacyLine::Invalid(format!("expected two values, found {}", parts.len()));
+    }
 
-        if parts.len() < 2 {
-            //println!("Invalid line \"{}\", ignoring", line);
-            continue;
+    let x = match parts[0].parse::<u16>() {
+        Ok(val) => val,
+        Err(e) => {
+            return LegacyLine::Invalid(format!("could not parse value {}: {}", parts[0], e));
         }
+    };
 
-        let x = match parts[0].parse::<u16>() {
-            Ok(val) => val,
-            Err(_) => {
-                //println!("Could not parse value {}: {}, ignoring", parts[0], e);
-                continue;
-            }
-        };
+    let y = match parts[1].parse::<u16>() {
+        Ok(val) => val,
+        Err(e) => {
+            return LegacyLine::Invalid(format!("could not parse value {}: {}", parts[1], e));
+        }
+    };
 
-        let y = match parts[1].parse::<u16>() {
-            Ok(val) => val,
-            Err(_) => {
-                //println!("Could not parse value {}: {}, ignoring", parts[1], e);
-                continue;
-            }
-        };
+    LegacyLine::Point(x, y)
+}
+
+fn from_legacy_string(conf: &str) -> Result<Config, String> {
+    let mut curve: Vec<(u16, u16)> = Vec::new();
 
-        curve.push((x, y));
+    for (no, line) in conf.lines().enumerate() {
+        match parse_legacy_line(line) {
+            LegacyLine::Point(x, y) => curve.push((x, y)),
+            LegacyLine::Invalid(reason) => {
+                warn!("Ignoring invalid line {} \"{}\": {}", no+1, line.trim(), reason);
+            },
+            LegacyLine::Blank | LegacyLine::Comment(_) => {}
+        }
     }
+
     if curve.len() < 2 {
         Err("At least two points are required for the curve".to_string())
     } else {
         Ok(Config::Legacy(GpuConfig {
             gpus: vec![LegacyConf { points: curve }],
+            profiles: Vec::new(),
+            hooks: Vec::new(),
         }))
     }
 }
+
+/// Result of converting a legacy configuration file to TOML
+pub struct Migration {
+    /// The equivalent TOML configuration
+    pub toml: String,
+    /// Description of every line that could not be converted
+    pub skipped: Vec<String>,
+}
+
+/// Converts a legacy whitespace delimited configuration to the TOML format.
+/// Comments are kept in place within the `points` array; lines that cannot
+/// be parsed are left out and reported in `Migration::skipped`.
+pub fn migrate_legacy_string(conf: &str) -> Result<Migration, String> {
+    if !might_be_legacy_string(conf) {
+        return Err("not a legacy configuration file".to_string());
+    }
+
+    let mut points = 0;
+    let mut skipped = Vec::new();
+    let mut toml = String::from("[[gpu]]\nid = 0\nenabled = true\npoints = [\n");
+
+    for (no, line) in conf.lines().enumerate() {
+        match parse_legacy_line(line) {
+            LegacyLine::Point(x, y) => {
+                points += 1;
+                toml.push_str(&format!("    [{}, {}],\n", x, y));
+            },
+            LegacyLine::Comment(c) => {
+                toml.push_str(&format!("    {}\n", c));
+            },
+            LegacyLine::Invalid(reason) => {
+                skipped.push(format!("line {}: \"{}\": {}", no+1, line.trim(), reason));
+            },
+            LegacyLine::Blank => {}
+        }
+    }
+
+    toml.push_str("]\n");
+
+    if points < 2 {
+        return Err("At least two points are required for the curve".to_string());
+    }
+
+    Ok(Migration { toml, skipped })
+}
+
+#[test]
+fn test_migrate_legacy_string() {
+    let legacy = "# my curve\n30 20\n\n40 30\nfoo bar\n50\n60 40 extra\n# hot\n80 80\n";
+
+    let m = migrate_legacy_string(legacy).unwrap();
+
+    assert_eq!(m.skipped.len(), 3);
+    assert!(m.skipped[0].starts_with("line 5: \"foo bar\""));
+    assert!(m.skipped[1].starts_with("line 6: \"50\""));
+    assert_eq!(m.skipped[2], "line 7: \"60 40 extra\": expected two values, found 3");
+    assert!(m.toml.contains("    # my curve\n    [30, 20],\n"));
+    assert!(m.toml.contains("    # hot\n    [80, 80],\n"));
+
+    let cfg = from_string(&m.toml).unwrap();
+    assert!(matches!(cfg, Config::Toml(_)));
+    assert_eq!(cfg.points(0), &vec![(30, 20), (40, 30), (80, 80)]);
+}
+
+#[test]
+fn test_migrate_toml_string() {
+    assert!(migrate_legacy_string("[[gpu]]\npoints = [[1, 2], [3, 4]]").is_err());
+}
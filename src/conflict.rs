@@ -0,0 +1,212 @@
+use std::time::{Duration, Instant};
+
+use status::ConflictData;
+
+/// Minutes to yield to another program unless configured otherwise
+const DEFAULT_YIELD_MINUTES: u64 = 5;
+
+/// What another program changed behind the back of nvfancontrol
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    /// The control mode of the GPU
+    Mode,
+    /// The level of one or more coolers
+    Level,
+}
+
+impl ConflictKind {
+    /// Name of the kind as in the status document
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ConflictKind::Mode => "mode",
+            ConflictKind::Level => "level",
+        }
+    }
+}
+
+/// A change made by another program between two updates
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interference {
+    pub kind: ConflictKind,
+    /// Coolers whose level was changed; empty for the control mode
+    pub coolers: Vec<u32>,
+}
+
+/// What happens once another program is found to control the fans
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    /// Leave the fans to the other program for the given time
+    Yield(Duration),
+    /// Write the control mode and levels again right away
+    Reassert,
+    /// Only log the conflict; levels changed by the other program are kept
+    /// until a different one is requested
+    Log,
+}
+
+impl ConflictPolicy {
+    /// Parses `yield[:MINUTES]`, `reassert` or `log`
+    pub fn parse(s: &str) -> Result<ConflictPolicy, String> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next().unwrap_or(""), parts.next()) {
+            ("yield", None) => Ok(ConflictPolicy::Yield(Duration::from_secs(DEFAULT_YIELD_MINUTES * 60))),
+            ("yield", Some(m)) => match m.parse::<u64>() {
+                Ok(m) if m > 0 => Ok(ConflictPolicy::Yield(Duration::from_secs(m * 60))),
+                _ => Err(format!("Invalid time to yield \"{}\"; expected minutes > 0", m)),
+            },
+            ("reassert", None) => Ok(ConflictPolicy::Reassert),
+            ("log", None) => Ok(ConflictPolicy::Log),
+            _ => Err(format!("Invalid conflict policy \"{}\"; expected yield[:MINUTES], \
+                              reassert or log", s)),
+        }
+    }
+
+    /// Name of the policy as in the status document
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ConflictPolicy::Yield(_) => "yield",
+            ConflictPolicy::Reassert => "reassert",
+            ConflictPolicy::Log => "log",
+        }
+    }
+}
+
+/// Change of the conflict state after an update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictEvent {
+    /// Another program changed the fans where it did not before
+    Started,
+    /// It changed them again
+    Continued,
+    /// An update went by without changes
+    Ended,
+}
+
+/// Keeps track of another program controlling the same fans, e.g.
+/// `nvidia-settings` or GreenWithEnvy. A conflict lasts as long as every
+/// update finds the fans changed; with `ConflictPolicy::Yield` it lasts until
+/// the fans are taken back.
+pub struct ConflictTracker {
+    policy: ConflictPolicy,
+    current: Option<Interference>,
+    /// End of the time yielded to the other program
+    paused_until: Option<Instant>,
+}
+
+impl ConflictTracker {
+
+    pub fn new(policy: ConflictPolicy) -> ConflictTracker {
+        ConflictTracker { policy, current: None, paused_until: None }
+    }
+
+    pub fn policy(&self) -> ConflictPolicy {
+        self.policy
+    }
+
+    /// Whether the fans are left to another program at `now`
+    pub fn yielding(&self, now: Instant) -> bool {
+        self.paused_until.is_some_and(|until| now < until)
+    }
+
+    /// Ends a pause that is over at `now`; returns whether it did
+    pub fn resume(&mut self, now: Instant) -> bool {
+        match self.paused_until {
+            Some(until) if now >= until => self.take_back(),
+            _ => false,
+        }
+    }
+
+    /// Ends a pause early; returns whether there was one
+    pub fn take_back(&mut self) -> bool {
+        let paused = self.paused_until.take().is_some();
+        if paused {
+            self.current = None;
+        }
+        paused
+    }
+
+    /// Records what an update found changed since the previous one
+    pub fn observe(&mut self, found: Option<Interference>, now: Instant) -> Option<ConflictEvent> {
+        let event = match (&found, &self.current) {
+            (&Some(_), &None) => Some(ConflictEvent::Started),
+            (&Some(_), &Some(_)) => Some(ConflictEvent::Continued),
+            (&None, &Some(_)) => Some(ConflictEvent::Ended),
+            (&None, &None) => None,
+        };
+        if let (true, ConflictPolicy::Yield(time)) = (found.is_some(), self.policy) {
+            self.paused_until = Some(now + time);
+        }
+        self.current = found;
+        event
+    }
+
+    /// The current conflict as reported in the status
+    pub fn status(&self, now: Instant) -> Option<ConflictData> {
+        self.current.as_ref().map(|c| ConflictData {
+            kind: c.kind,
+            coolers: c.coolers.clone(),
+            policy: self.policy.name().to_string(),
+            resume_in: self.paused_until.map(|until| until.saturating_duration_since(now).as_secs()),
+        })
+    }
+}
+
+#[test]
+fn test_parse_conflict_policies() {
+    let minutes = |m: u64| Duration::from_secs(m * 60);
+    let cases = [
+        ("yield", Some(ConflictPolicy::Yield(minutes(DEFAULT_YIELD_MINUTES)))),
+        ("yield:30", Some(ConflictPolicy::Yield(minutes(30)))),
+        ("reassert", Some(ConflictPolicy::Reassert)),
+        ("log", Some(ConflictPolicy::Log)),
+        ("yield:0", None),
+        ("yield:", None),
+        ("log:5", None),
+        ("fight", None),
+    ];
+
+    for &(s, expected) in cases.iter() {
+        assert_eq!(ConflictPolicy::parse(s).ok(), expected, "{:?}", s);
+    }
+}
+
+#[test]
+fn test_yield_to_other_program() {
+    let level = || Some(Interference { kind: ConflictKind::Level, coolers: vec![1] });
+    let mut conflicts = ConflictTracker::new(ConflictPolicy::Yield(Duration::from_secs(60)));
+    let t0 = Instant::now();
+    let at = |s: u64| t0 + Duration::from_secs(s);
+
+    assert_eq!(conflicts.observe(None, at(0)), None);
+    assert_eq!(conflicts.observe(level(), at(2)), Some(ConflictEvent::Started));
+    assert!(conflicts.yielding(at(2)));
+    assert!(!conflicts.resume(at(61)));
+
+    let status = conflicts.status(at(32)).unwrap();
+    assert_eq!((status.kind, status.coolers, status.resume_in), (ConflictKind::Level, vec![1], Some(30)));
+    assert_eq!(status.policy, "yield");
+
+    assert!(conflicts.resume(at(62)));
+    assert!(!conflicts.yielding(at(62)));
+    assert_eq!(conflicts.status(at(62)), None);
+
+    // Still there after taking the fans back
+    assert_eq!(conflicts.observe(level(), at(64)), Some(ConflictEvent::Started));
+    assert!(conflicts.take_back());
+    assert!(!conflicts.take_back());
+}
+
+#[test]
+fn test_log_conflicts() {
+    let mode = || Some(Interference { kind: ConflictKind::Mode, coolers: Vec::new() });
+    let mut conflicts = ConflictTracker::new(ConflictPolicy::Log);
+    let t0 = Instant::now();
+
+    assert_eq!(conflicts.observe(mode(), t0), Some(ConflictEvent::Started));
+    assert_eq!(conflicts.observe(mode(), t0), Some(ConflictEvent::Continued));
+    assert!(!conflicts.yielding(t0));
+    assert_eq!(conflicts.status(t0).unwrap().resume_in, None);
+    assert_eq!(conflicts.observe(None, t0), Some(ConflictEvent::Ended));
+    assert_eq!(conflicts.status(t0), None);
+}
@@ -0,0 +1,353 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::channel::{MatchingReceiver, Sender as DBusSender};
+use dbus::message::{MatchRule, SignalArgs};
+use dbus::MethodErr;
+use dbus_crossroads::Crossroads;
+
+use server::{self, Command, Request, RpcError};
+use status::Status;
+
+/// Well-known name of the service
+pub const BUS_NAME: &str = "org.nvfancontrol.Daemon";
+/// Path of the object exposing `INTERFACE`
+pub const OBJECT_PATH: &str = "/org/nvfancontrol/Daemon";
+/// Interface with the status of the controlled GPU
+pub const INTERFACE: &str = "org.nvfancontrol.Daemon";
+/// How often pending messages and status updates are handled
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Bus on which the service is exposed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bus {
+    System,
+    Session,
+}
+
+impl Bus {
+    pub fn parse(bus: &str) -> Result<Bus, String> {
+        match bus {
+            "system" => Ok(Bus::System),
+            "session" => Ok(Bus::Session),
+            b => Err(format!("Invalid bus \"{}\"; expected \"system\" or \"session\"", b))
+        }
+    }
+}
+
+/// Values of the properties of `INTERFACE`
+#[derive(Debug, Clone, PartialEq)]
+struct Properties {
+    gpu: u32,
+    name: String,
+    temperature: i32,
+    target_speed: i32,
+    speeds: Vec<i32>,
+    rpms: Vec<i32>,
+    mode: String,
+    profile: String,
+    timestamp: i64,
+}
+
+impl Properties {
+    fn from_status(status: &Status, gpu: u32) -> Option<Properties> {
+        status.gpus.iter().find(|g| g.index == gpu).map(|g| Properties {
+            gpu,
+            name: g.name.clone(),
+            temperature: g.temp,
+            target_speed: g.target.unwrap_or(-1),
+            speeds: g.coolers.iter().map(|c| c.speed).collect(),
+            rpms: g.coolers.iter().map(|c| c.rpm).collect(),
+            mode: g.mode.map(|m| format!("{:?}", m)).unwrap_or_default(),
+            profile: g.profile.clone().unwrap_or_default(),
+            timestamp: status.timespec,
+        })
+    }
+
+    /// Properties whose value differs from `prev`; all of them without `prev`
+    fn changed(&self, prev: Option<&Properties>) -> PropMap {
+        let mut map = PropMap::new();
+        {
+            let mut add = |name: &str, unchanged: bool, value: Box<dyn RefArg>| {
+                if !unchanged {
+                    map.insert(name.to_string(), Variant(value));
+                }
+            };
+            add("Gpu", prev.is_some_and(|p| p.gpu == self.gpu), Box::new(self.gpu));
+            add("Name", prev.is_some_and(|p| p.name == self.name), Box::new(self.name.clone()));
+            add("Temperature", prev.is_some_and(|p| p.temperature == self.temperature),
+                Box::new(self.temperature));
+            add("TargetSpeed", prev.is_some_and(|p| p.target_speed == self.target_speed),
+                Box::new(self.target_speed));
+            add("Speeds", prev.is_some_and(|p| p.speeds == self.speeds), Box::new(self.speeds.clone()));
+            add("Rpms", prev.is_some_and(|p| p.rpms == self.rpms), Box::new(self.rpms.clone()));
+            add("Mode", prev.is_some_and(|p| p.mode == self.mode), Box::new(self.mode.clone()));
+            add("Profile", prev.is_some_and(|p| p.profile == self.profile),
+                Box::new(self.profile.clone()));
+            add("Timestamp", prev.is_some_and(|p| p.timestamp == self.timestamp),
+                Box::new(self.timestamp));
+        }
+        map
+    }
+}
+
+/// Object data shared by all property and method handlers
+struct Service {
+    gpu: u32,
+    data: Arc<RwLock<Status>>,
+    tx: Sender<Request>,
+}
+
+impl Service {
+    fn properties(&self) -> Result<Properties, MethodErr> {
+        Properties::from_status(&self.data.read().unwrap(), self.gpu)
+            .ok_or_else(|| MethodErr::failed(&format!("No status for GPU {}", self.gpu)))
+    }
+
+    fn dispatch(&self, command: Command) -> Result<(), MethodErr> {
+        server::dispatch(command, &self.tx).map(|_| ()).map_err(method_error)
+    }
+}
+
+fn method_error(e: RpcError) -> MethodErr {
+    let name = match e.code {
+        server::INVALID_PARAMS => "org.freedesktop.DBus.Error.InvalidArgs",
+        server::NOT_PERMITTED => "org.nvfancontrol.Error.NotPermitted",
+        server::UNAVAILABLE => "org.nvfancontrol.Error.Unavailable",
+        _ => "org.nvfancontrol.Error.Failed",
+    };
+    (name, e.message).into()
+}
+
+/// Connects to `bus` and claims `BUS_NAME`
+pub fn connect(bus: Bus) -> Result<Connection, String> {
+    let conn = match bus {
+        Bus::System => Connection::new_system(),
+        Bus::Session => Connection::new_session(),
+    }.map_err(|e| format!("Could not connect to the {:?} bus: {}", bus, e))?;
+    claim_name(&conn)?;
+    Ok(conn)
+}
+
+fn claim_name(conn: &Connection) -> Result<(), String> {
+    use dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
+
+    match conn.request_name(BUS_NAME, false, true, true) {
+        Ok(RequestNameReply::PrimaryOwner) | Ok(RequestNameReply::AlreadyOwner) => Ok(()),
+        Ok(_) => Err(format!("D-Bus name {} is already taken", BUS_NAME)),
+        Err(e) => Err(format!("Could not claim D-Bus name {}: {}", BUS_NAME, e)),
+    }
+}
+
+fn make_crossroads(service: Service) -> Crossroads {
+    let mut cr = Crossroads::new();
+
+    let iface = cr.register(INTERFACE, |b| {
+        b.property("Gpu").get(|_, s: &mut Service| Ok(s.properties()?.gpu));
+        b.property("Name").get(|_, s: &mut Service| Ok(s.properties()?.name));
+        b.property("Temperature").get(|_, s: &mut Service| Ok(s.properties()?.temperature));
+        b.property("TargetSpeed").get(|_, s: &mut Service| Ok(s.properties()?.target_speed));
+        b.property("Speeds").get(|_, s: &mut Service| Ok(s.properties()?.speeds));
+        b.property("Rpms").get(|_, s: &mut Service| Ok(s.properties()?.rpms));
+        b.property("Mode").get(|_, s: &mut Service| Ok(s.properties()?.mode));
+        b.property("Profile").get(|_, s: &mut Service| Ok(s.properties()?.profile));
+        b.property("Timestamp").get(|_, s: &mut Service| Ok(s.properties()?.timestamp));
+
+        b.method("SetProfile", ("name",), (), |_, s: &mut Service, (name,): (String,)| {
+            s.dispatch(Command::SetProfile(name))
+        });
+        b.method("ReleaseToAuto", (), (), |_, s: &mut Service, _: ()| {
+            s.dispatch(Command::ReleaseToAuto)
+        });
+    });
+    cr.insert(OBJECT_PATH, &[iface], service);
+
+    cr
+}
+
+/// Serves `INTERFACE` for GPU `gpu` on `conn` until `running` is cleared.
+/// Properties are read from `data` and `PropertiesChanged` is emitted
+/// whenever it has been updated; methods are forwarded to the main loop
+/// over `tx`.
+pub fn serve(conn: Connection, gpu: u32, data: Arc<RwLock<Status>>, tx: Sender<Request>,
+             running: &'static AtomicBool) -> Result<(), String> {
+    info!("Serving {} on D-Bus", BUS_NAME);
+
+    let mut cr = make_crossroads(Service { gpu, data: data.clone(), tx });
+    conn.start_receive(MatchRule::new_method_call(), Box::new(move |msg, conn| {
+        if cr.handle_message(msg, conn).is_err() {
+            debug!("Could not handle D-Bus message");
+        }
+        true
+    }));
+
+    let mut last: Option<Properties> = None;
+    while running.load(Ordering::Relaxed) {
+        conn.process(POLL_INTERVAL).map_err(|e| format!("D-Bus error: {}", e))?;
+
+        let current = match Properties::from_status(&data.read().unwrap(), gpu) {
+            Some(p) => p,
+            None => continue
+        };
+        if last.as_ref() == Some(&current) {
+            continue;
+        }
+
+        let signal = PropertiesPropertiesChanged {
+            interface_name: INTERFACE.to_string(),
+            changed_properties: current.changed(last.as_ref()),
+            invalidated_properties: Vec::new(),
+        };
+        if conn.send(signal.to_emit_message(&OBJECT_PATH.into())).is_err() {
+            debug!("Could not emit PropertiesChanged");
+        }
+        last = Some(current);
+    }
+
+    debug!("D-Bus service terminated");
+    Ok(())
+}
+
+#[cfg(test)]
+struct TestBus {
+    daemon: ::std::process::Child,
+    address: String,
+    dir: ::std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl TestBus {
+    /// Starts a private `dbus-daemon`; `None` if it is not installed
+    fn start() -> Option<TestBus> {
+        use std::fs;
+        use std::io::{BufRead, BufReader};
+        use std::process::{Command, Stdio};
+
+        let dir = ::std::env::temp_dir().join(format!("nvfancontrol-dbus-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("bus.conf");
+        fs::write(&config, format!(
+            "<busconfig><type>session</type><listen>unix:dir={}</listen>\
+             <policy context=\"default\"><allow send_destination=\"*\"/>\
+             <allow receive_sender=\"*\"/><allow own=\"*\"/></policy></busconfig>",
+            dir.display())).unwrap();
+
+        let mut daemon = match Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .args(["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn() {
+            Ok(d) => d,
+            Err(e) => {
+                eprintln!("Skipping D-Bus test; could not start dbus-daemon: {}", e);
+                return None;
+            }
+        };
+
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        Some(TestBus { daemon, address: address.trim().to_string(), dir })
+    }
+
+    fn connect(&self) -> Connection {
+        let mut channel = dbus::channel::Channel::open_private(&self.address).unwrap();
+        channel.register().unwrap();
+        Connection::from(channel)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+        let _ = ::std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn test_service_on_private_bus() {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Instant;
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties as PropertiesProxy;
+    use status::make_test_status;
+
+    static TEST_RUNNING: AtomicBool = AtomicBool::new(true);
+
+    let bus = match TestBus::start() {
+        Some(b) => b,
+        None => return
+    };
+
+    let (tx, rx) = mpsc::channel::<Request>();
+    let data = Arc::new(RwLock::new(make_test_status()));
+
+    // Stands in for the main loop
+    thread::spawn(move || {
+        for req in rx {
+            let reply = match req.command {
+                Command::SetProfile(ref name) if name == "silent" => Ok(::serde_json::Value::Null),
+                Command::SetProfile(name) =>
+                    Err(RpcError::new(server::INVALID_PARAMS, format!("unknown profile \"{}\"", name))),
+                Command::ReleaseToAuto =>
+                    Err(RpcError::new(server::NOT_PERMITTED, "fan control disabled (monitor-only)")),
+                _ => Ok(::serde_json::Value::Null),
+            };
+            req.reply.send(reply).unwrap();
+        }
+    });
+
+    let service_conn = bus.connect();
+    claim_name(&service_conn).unwrap();
+    let srv_data = data.clone();
+    let service = thread::spawn(move || serve(service_conn, 1, srv_data, tx, &TEST_RUNNING));
+
+    let client = bus.connect();
+    let (signal_tx, signal_rx) = mpsc::channel();
+    let rule = PropertiesPropertiesChanged::match_rule(None, Some(&OBJECT_PATH.into())).static_clone();
+    client.add_match(rule, move |s: PropertiesPropertiesChanged, _: &Connection, _: &dbus::Message| {
+        signal_tx.send(s.changed_properties).unwrap();
+        true
+    }).unwrap();
+
+    let proxy = client.with_proxy(BUS_NAME, OBJECT_PATH, Duration::from_secs(5));
+    assert_eq!(proxy.get::<i32>(INTERFACE, "Temperature").unwrap(), 55);
+    assert_eq!(proxy.get::<Vec<i32>>(INTERFACE, "Rpms").unwrap(), vec![1200, 1250]);
+    assert_eq!(proxy.get::<String>(INTERFACE, "Mode").unwrap(), "Manual");
+    assert_eq!(proxy.get::<String>(INTERFACE, "Profile").unwrap(), "silent");
+
+    let () = proxy.method_call(INTERFACE, "SetProfile", ("silent",)).unwrap();
+    let err = proxy.method_call::<(), _, _, _>(INTERFACE, "SetProfile", ("turbo",)).unwrap_err();
+    assert_eq!(err.name(), Some("org.freedesktop.DBus.Error.InvalidArgs"));
+    let err = proxy.method_call::<(), _, _, _>(INTERFACE, "ReleaseToAuto", ()).unwrap_err();
+    assert_eq!(err.name(), Some("org.nvfancontrol.Error.NotPermitted"));
+
+    // The next tick only changes the temperature and the timestamp
+    {
+        let mut status = data.write().unwrap();
+        status.timespec += 2;
+        status.gpus[1].temp = 60;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let changed = loop {
+        assert!(Instant::now() < deadline, "no PropertiesChanged signal received");
+        client.process(POLL_INTERVAL).unwrap();
+        if let Ok(changed) = signal_rx.try_recv() {
+            if changed.get("Temperature").and_then(|v| v.0.as_i64()) == Some(60) {
+                break changed;
+            }
+        }
+    };
+    assert_eq!(changed.len(), 2);
+    assert!(changed.contains_key("Timestamp"));
+
+    TEST_RUNNING.store(false, Ordering::Relaxed);
+    service.join().unwrap().unwrap();
+}
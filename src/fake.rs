@@ -0,0 +1,163 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use nvctrl::{NvFanController, NVCtrlFanControlState, ThermalThresholds};
+
+/// Controller methods answered without asking the X server
+const LOCAL_CALLS: [&str; 2] = ["gpu_count", "gpu_coolers"];
+
+/// State of the single GPU of a `FakeController`
+#[derive(Debug, Clone)]
+pub struct FakeGpu {
+    pub temp: i32,
+    pub mode: NVCtrlFanControlState,
+    /// Cooler ids and their levels (%)
+    pub coolers: Vec<(u32, i32)>,
+    /// Lowest and highest level the coolers actually run at
+    pub level_range: (i32, i32),
+    /// Coolers that report 0 RPM whatever their level
+    pub stalled: Vec<u32>,
+    /// Coolers whose RPM cannot be read
+    pub no_rpm: Vec<u32>,
+    /// Driver version reported by the controller
+    pub version: &'static str,
+    /// `None` if the thresholds cannot be read
+    pub thresholds: Option<ThermalThresholds>,
+    /// Name of a controller method that panics when called
+    pub panic_on: Option<&'static str>,
+    /// Controller methods called so far, in order
+    pub calls: Vec<&'static str>,
+}
+
+/// An `NvFanController` for tests. The state of the GPU is shared between
+/// clones so it can be inspected after the controller has been handed over.
+#[derive(Clone)]
+pub struct FakeController {
+    gpu: Arc<Mutex<FakeGpu>>,
+}
+
+impl FakeGpu {
+    pub fn new(temp: i32, mode: NVCtrlFanControlState, coolers: Vec<(u32, i32)>) -> FakeGpu {
+        let thresholds = ThermalThresholds { slowdown: 90, default_slowdown: 90, max: Some(100) };
+        FakeGpu { temp, mode, coolers, level_range: (0, 100), stalled: Vec::new(),
+                  no_rpm: Vec::new(), version: "550.54.14", thresholds: Some(thresholds),
+                  panic_on: None, calls: Vec::new() }
+    }
+
+    fn level(&self, id: u32) -> Result<i32, String> {
+        self.coolers.iter().find(|c| c.0 == id).map(|c| c.1)
+            .ok_or_else(|| format!("Invalid cooler id {}", id))
+    }
+}
+
+impl FakeController {
+
+    pub fn new(gpu: FakeGpu) -> FakeController {
+        FakeController { gpu: Arc::new(Mutex::new(gpu)) }
+    }
+
+    /// The state of the GPU; still accessible after an injected panic
+    pub fn gpu(&self) -> MutexGuard<'_, FakeGpu> {
+        self.gpu.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn call(&self, name: &'static str) -> MutexGuard<'_, FakeGpu> {
+        let mut gpu = self.gpu();
+        gpu.calls.push(name);
+        if gpu.panic_on == Some(name) {
+            drop(gpu);
+            panic!("injected panic in {}", name);
+        }
+        gpu
+    }
+
+    fn check_gpu(gpu: u32) -> Result<(), String> {
+        if gpu == 0 { Ok(()) } else { Err(format!("Invalid GPU id {}", gpu)) }
+    }
+}
+
+impl NvFanController for FakeController {
+    fn get_temp(&self, gpu: u32) -> Result<i32, String> {
+        FakeController::check_gpu(gpu)?;
+        Ok(self.call("get_temp").temp)
+    }
+
+    fn get_ctrl_status(&self, gpu: u32) -> Result<NVCtrlFanControlState, String> {
+        FakeController::check_gpu(gpu)?;
+        Ok(self.call("get_ctrl_status").mode)
+    }
+
+    fn set_ctrl_type(&self, gpu: u32, state: NVCtrlFanControlState) -> Result<(), String> {
+        FakeController::check_gpu(gpu)?;
+        self.call("set_ctrl_type").mode = state;
+        Ok(())
+    }
+
+    fn get_fanspeed(&self, gpu: u32, id: u32) -> Result<i32, String> {
+        FakeController::check_gpu(gpu)?;
+        self.call("get_fanspeed").level(id)
+    }
+
+    fn get_fanspeed_rpm(&self, gpu: u32, id: u32) -> Result<i32, String> {
+        FakeController::check_gpu(gpu)?;
+        let gpu = self.call("get_fanspeed_rpm");
+        if gpu.no_rpm.contains(&id) {
+            return Err(format!("Cannot read the RPM of cooler {}", id));
+        }
+        if gpu.stalled.contains(&id) { gpu.level(id).map(|_| 0) } else { gpu.level(id).map(|l| l * 30) }
+    }
+
+    fn set_fanspeed(&self, gpu: u32, id: u32, speed: i32) -> Result<(), String> {
+        FakeController::check_gpu(gpu)?;
+        let mut state = self.call("set_fanspeed");
+        let (low, high) = state.level_range;
+        match state.coolers.iter_mut().find(|c| c.0 == id) {
+            Some(c) => {
+                // Silently clamped like some drivers do
+                c.1 = speed.max(low).min(high);
+                Ok(())
+            },
+            None => Err(format!("Invalid cooler id {}", id)),
+        }
+    }
+
+    fn get_thermal_thresholds(&self, gpu: u32) -> Result<ThermalThresholds, String> {
+        FakeController::check_gpu(gpu)?;
+        self.call("get_thermal_thresholds").thresholds
+            .ok_or_else(|| "Thermal thresholds not available".to_string())
+    }
+
+    fn get_version(&self) -> Result<String, String> {
+        Ok(self.call("get_version").version.to_string())
+    }
+
+    fn get_adapter(&self, id: u32) -> Result<String, String> {
+        FakeController::check_gpu(id)?;
+        drop(self.call("get_adapter"));
+        Ok("Fake GPU".to_string())
+    }
+
+    fn get_utilization(&self, id: u32) -> Result<HashMap<&str, i32>, String> {
+        FakeController::check_gpu(id)?;
+        drop(self.call("get_utilization"));
+        let mut utilization = HashMap::new();
+        utilization.insert("graphics", 10);
+        Ok(utilization)
+    }
+
+    fn gpu_count(&self) -> Result<u32, String> {
+        drop(self.call("gpu_count"));
+        Ok(1)
+    }
+
+    fn gpu_coolers(&self, gpu: u32) -> Result<Cow<'_, Vec<u32>>, String> {
+        FakeController::check_gpu(gpu)?;
+        Ok(Cow::Owned(self.call("gpu_coolers").coolers.iter().map(|c| c.0).collect()))
+    }
+
+    /// Calls that reach the X server of the real controller
+    fn round_trips(&self) -> Option<u64> {
+        Some(self.gpu().calls.iter().filter(|c| !LOCAL_CALLS.contains(*c)).count() as u64)
+    }
+}
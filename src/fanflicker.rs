@@ -5,6 +5,7 @@ const FLICKER_TEMP_MAX: i32 = 75;
 const FLICKER_TEMP_MAX_REASON: &str = "\n        \
 below the limit speed adjustments are not instant, so the temperature might temporarily rise higher";
 
+#[derive(Clone, Copy)]
 pub struct FanFlickerRange {
     pub minimum_allowed: i32,
     pub fickering_starts: i32
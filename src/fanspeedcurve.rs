@@ -41,6 +41,10 @@ impl FanspeedCurve {
         }
     }
 
+    pub fn points(&self) -> &[(u16, u16)] {
+        &self.0
+    }
+
      pub fn minspeed(&self) -> i32 {
         self.0.first().unwrap().1 as i32
     }
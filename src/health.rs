@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// How long a fault condition has to hold before it is reported
+const FAULT_DELAY: Duration = Duration::from_secs(30);
+/// Lowest level (%) at which a cooler is expected to spin
+const MIN_SPIN_LEVEL: i32 = 30;
+/// A cooler is considered slow below this fraction of its expected RPM
+const LOW_RPM_FRACTION: f64 = 0.5;
+/// Healthy samples needed before the expected RPM is trusted
+const LEARN_SAMPLES: u32 = 10;
+/// Weight of a new sample in the learned RPM per percent of level
+const LEARN_WEIGHT: f64 = 0.1;
+
+/// A detected problem with a cooler
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FanFault {
+    /// Reports 0 RPM while driven at a speed it should spin at
+    Stalled,
+    /// Spins far slower than it used to at the same level
+    LowRpm,
+    /// Its level or RPM cannot be read
+    SensorDropout,
+}
+
+impl FanFault {
+    /// Name of the fault as in the status document
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FanFault::Stalled => "stalled",
+            FanFault::LowRpm => "low_rpm",
+            FanFault::SensorDropout => "sensor_dropout",
+        }
+    }
+}
+
+/// One reading of a cooler
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoolerSample {
+    pub id: u32,
+    /// Level written by nvfancontrol; `None` under auto control
+    pub commanded: Option<i32>,
+    /// Level reported by the driver; `None` if it could not be read
+    pub level: Option<i32>,
+    /// `None` if the RPM could not be read
+    pub rpm: Option<i32>,
+}
+
+#[derive(Default)]
+struct CoolerHealth {
+    /// Learned RPM per percent of level
+    rpm_per_level: f64,
+    samples: u32,
+    /// Condition that currently holds and since when
+    pending: Option<(FanFault, Instant)>,
+    fault: Option<FanFault>,
+    /// Whether the level and RPM have been read at least once
+    readable: bool,
+}
+
+/// Watches the coolers of a GPU for stalls, abnormally low RPM and readings
+/// that drop out after having worked. A fault is reported once its condition
+/// has held for 30 seconds and cleared on the first sample without it. Stalls
+/// and low RPM are only checked while nvfancontrol drives the coolers.
+#[derive(Default)]
+pub struct FanHealth {
+    coolers: BTreeMap<u32, CoolerHealth>,
+}
+
+impl CoolerHealth {
+
+    fn expected_rpm(&self, level: i32) -> Option<f64> {
+        if self.samples >= LEARN_SAMPLES {
+            Some(self.rpm_per_level * level as f64)
+        } else {
+            None
+        }
+    }
+
+    /// The fault indicated by `sample` alone; learns the RPM of the cooler
+    /// from healthy samples
+    fn check(&mut self, sample: &CoolerSample) -> Option<FanFault> {
+        let rpm = match (sample.level, sample.rpm) {
+            (Some(_), Some(rpm)) if rpm >= 0 => rpm,
+            // A cooler that never reported its speed has no sensor to lose
+            _ if !self.readable => return None,
+            _ => return Some(FanFault::SensorDropout),
+        };
+        self.readable = true;
+        let level = match sample.commanded {
+            Some(l) if l >= MIN_SPIN_LEVEL => l,
+            _ => return None,
+        };
+
+        if rpm == 0 {
+            return Some(FanFault::Stalled);
+        }
+        if self.expected_rpm(level).is_some_and(|e| (rpm as f64) < e * LOW_RPM_FRACTION) {
+            return Some(FanFault::LowRpm);
+        }
+
+        let ratio = rpm as f64 / level as f64;
+        self.rpm_per_level = if self.samples == 0 {
+            ratio
+        } else {
+            self.rpm_per_level + LEARN_WEIGHT * (ratio - self.rpm_per_level)
+        };
+        self.samples += 1;
+        None
+    }
+}
+
+impl FanHealth {
+
+    pub fn new() -> FanHealth {
+        FanHealth::default()
+    }
+
+    /// Feeds the readings of one update. Returns the coolers whose state
+    /// changed along with their new fault, `None` if they recovered.
+    pub fn update(&mut self, samples: &[CoolerSample], now: Instant)
+        -> Vec<(u32, Option<FanFault>)>
+    {
+        let mut changed = Vec::new();
+        for sample in samples {
+            let cooler = self.coolers.entry(sample.id).or_default();
+
+            let condition = cooler.check(sample);
+            let fault = match (condition, cooler.pending) {
+                (Some(c), Some((p, since))) if c == p =>
+                    if now.duration_since(since) >= FAULT_DELAY { Some(c) } else { cooler.fault },
+                (Some(c), _) => {
+                    cooler.pending = Some((c, now));
+                    cooler.fault
+                },
+                (None, _) => {
+                    cooler.pending = None;
+                    None
+                },
+            };
+
+            if fault != cooler.fault {
+                cooler.fault = fault;
+                changed.push((sample.id, fault));
+            }
+        }
+        changed
+    }
+
+    /// The reported fault of cooler `id`, if any
+    pub fn fault(&self, id: u32) -> Option<FanFault> {
+        self.coolers.get(&id).and_then(|c| c.fault)
+    }
+
+    /// Coolers with a reported fault
+    pub fn faulty(&self) -> Vec<u32> {
+        self.coolers.iter().filter(|&(_, c)| c.fault.is_some()).map(|(id, _)| *id).collect()
+    }
+}
+
+#[test]
+fn test_stall_and_dropout() {
+    let sample = |commanded, level, rpm| CoolerSample { id: 1, commanded, level, rpm };
+    let mut health = FanHealth::new();
+    let t0 = Instant::now();
+    let at = |s: u64| t0 + Duration::from_secs(s);
+
+    // Fans that stop under auto control or at low levels are fine
+    assert!(health.update(&[sample(None, Some(40), Some(0))], at(0)).is_empty());
+    assert!(health.update(&[sample(Some(20), Some(20), Some(0))], at(40)).is_empty());
+
+    assert!(health.update(&[sample(Some(60), Some(60), Some(0))], at(42)).is_empty());
+    assert!(health.update(&[sample(Some(60), Some(60), Some(0))], at(70)).is_empty());
+    assert_eq!(health.update(&[sample(Some(60), Some(60), Some(0))], at(72)),
+               vec![(1, Some(FanFault::Stalled))]);
+    assert!(health.update(&[sample(Some(60), Some(60), Some(0))], at(74)).is_empty());
+    assert_eq!(health.faulty(), vec![1]);
+
+    // Reported until the cooler spins again
+    assert_eq!(health.update(&[sample(Some(60), Some(60), Some(1800))], at(76)),
+               vec![(1, None)]);
+    assert_eq!(health.fault(1), None);
+
+    // A single failed read is not a dropout yet
+    assert!(health.update(&[sample(Some(60), Some(60), None)], at(78)).is_empty());
+    assert!(health.update(&[sample(Some(60), None, Some(1800))], at(100)).is_empty());
+    assert_eq!(health.update(&[sample(None, Some(40), Some(-1))], at(108)),
+               vec![(1, Some(FanFault::SensorDropout))]);
+
+    // Unlike a cooler that never reports its RPM
+    let never = |rpm| CoolerSample { id: 2, commanded: None, level: Some(40), rpm };
+    assert!(health.update(&[never(None)], at(0)).is_empty());
+    assert!(health.update(&[never(None)], at(100)).is_empty());
+    assert_eq!(health.fault(2), None);
+}
+
+#[test]
+fn test_low_rpm() {
+    let sample = |id, level, rpm| CoolerSample { id, commanded: Some(level), level: Some(level),
+                                                 rpm: Some(rpm) };
+    let mut health = FanHealth::new();
+    let t0 = Instant::now();
+    let at = |s: u64| t0 + Duration::from_secs(s);
+
+    // 30 RPM per percent
+    for i in 0..LEARN_SAMPLES as u64 {
+        let level = 40 + i as i32;
+        assert!(health.update(&[sample(0, level, level * 30), sample(1, level, level * 30)],
+                              at(i * 2)).is_empty());
+    }
+
+    // Cooler 1 only reaches a third of that from now on
+    assert!(health.update(&[sample(0, 60, 1800), sample(1, 60, 600)], at(20)).is_empty());
+    assert_eq!(health.update(&[sample(0, 70, 2100), sample(1, 70, 700)], at(50)),
+               vec![(1, Some(FanFault::LowRpm))]);
+    // Slightly slower than learned is fine
+    assert!(health.update(&[sample(0, 70, 1700), sample(1, 70, 700)], at(52)).is_empty());
+    assert_eq!(health.faulty(), vec![1]);
+}
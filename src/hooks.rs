@@ -0,0 +1,341 @@
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use config::{HookConf, HookEvent};
+use health::FanFault;
+use nvctrl::NVCtrlFanControlState;
+use serde_json;
+use status::GPUData;
+
+/// Prefix of the environment variables passed to hooks. It differs from the
+/// `NVFANCONTROL_` variables so a hook that starts nvfancontrol does not
+/// change its configuration by accident.
+const ENV_PREFIX: &str = "NVFC_";
+
+struct HookState {
+    conf: HookConf,
+    /// Since when the condition of a temperature or stall hook holds
+    since: Option<Instant>,
+    /// Whether the hook already fired for the current condition
+    fired: bool,
+    last_run: Option<Instant>,
+    /// Events dropped by the rate limit since the last run
+    suppressed: u64,
+    child: Option<Child>,
+}
+
+/// Runs the configured hooks when their events occur on the controlled GPU.
+///
+/// Temperature and stall events fire once when their condition has held for
+/// `debounce` seconds and re-arm when it clears. A hook does not run while
+/// its previous command is still running or within `min_interval` seconds
+/// of its last run; such events are dropped and counted instead.
+pub struct Hooks {
+    hooks: Vec<HookState>,
+    previous: Option<GPUData>,
+}
+
+fn mode_name(mode: Option<NVCtrlFanControlState>) -> &'static str {
+    match mode {
+        Some(NVCtrlFanControlState::Auto) => "auto",
+        Some(NVCtrlFanControlState::Manual) => "manual",
+        None => "unknown",
+    }
+}
+
+fn event_name(event: HookEvent) -> String {
+    serde_json::to_value(event).ok()
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_default()
+}
+
+/// Coolers with a `Stalled` fault
+fn stalled(gpu: &GPUData) -> Vec<u32> {
+    gpu.coolers.iter().filter(|c| c.fault == Some(FanFault::Stalled)).map(|c| c.id).collect()
+}
+
+/// Whether an edge triggered event occurred between `previous` and `gpu`
+fn occurred(event: HookEvent, previous: Option<&GPUData>, gpu: &GPUData,
+            error: Option<&str>) -> bool {
+    match event {
+        HookEvent::ModeChanged => previous.is_some_and(|p| p.mode != gpu.mode),
+        HookEvent::ProfileChanged => previous.is_some_and(|p| p.profile != gpu.profile),
+        HookEvent::Fanflicker =>
+            previous.is_some_and(|p| gpu.fanflicker_interventions > p.fanflicker_interventions),
+        HookEvent::UpdateError => error.is_some(),
+        HookEvent::TempAbove | HookEvent::TempBelow | HookEvent::FanStalled => false,
+    }
+}
+
+/// Environment variables describing an event to the hook command
+fn environment(conf: &HookConf, suppressed: u64, previous: Option<&GPUData>,
+               gpu: &GPUData, error: Option<&str>) -> Vec<(String, String)> {
+    let join = |values: Vec<String>| values.join(",");
+
+    let mut vars = vec![
+        ("EVENT", event_name(conf.event)),
+        ("GPU", gpu.index.to_string()),
+        ("NAME", gpu.name.clone()),
+        ("TEMP", gpu.temp.to_string()),
+        ("SPEEDS", join(gpu.coolers.iter().map(|c| c.speed.to_string()).collect())),
+        ("RPMS", join(gpu.coolers.iter().map(|c| c.rpm.to_string()).collect())),
+        ("MODE", mode_name(gpu.mode).to_string()),
+        ("FANFLICKER_INTERVENTIONS", gpu.fanflicker_interventions.to_string()),
+        ("UPDATE_ERRORS", gpu.update_errors.to_string()),
+        ("SUPPRESSED", suppressed.to_string()),
+    ];
+
+    if let Some(target) = gpu.target {
+        vars.push(("TARGET", target.to_string()));
+    }
+    if let Some(ref profile) = gpu.profile {
+        vars.push(("PROFILE", profile.clone()));
+    }
+    if let Some(threshold) = conf.threshold {
+        vars.push(("THRESHOLD", threshold.to_string()));
+    }
+    if let Some(p) = previous {
+        vars.push(("PREVIOUS_MODE", mode_name(p.mode).to_string()));
+        if let Some(ref profile) = p.profile {
+            vars.push(("PREVIOUS_PROFILE", profile.clone()));
+        }
+    }
+    if conf.event == HookEvent::FanStalled {
+        vars.push(("STALLED", join(stalled(gpu).iter().map(|id| id.to_string()).collect())));
+    }
+    if let Some(e) = error {
+        vars.push(("ERROR", e.to_string()));
+    }
+
+    vars.into_iter().map(|(k, v)| (format!("{}{}", ENV_PREFIX, k), v)).collect()
+}
+
+impl HookState {
+
+    fn new(conf: HookConf) -> HookState {
+        // Dropping below a temperature only counts after being above it
+        let fired = conf.event == HookEvent::TempBelow;
+        HookState { conf, since: None, fired, last_run: None, suppressed: 0, child: None }
+    }
+
+    /// Whether the condition of a level triggered hook holds; `None` for
+    /// edge triggered hooks
+    fn condition(&self, gpu: &GPUData) -> Option<bool> {
+        let threshold = self.conf.threshold.map(i32::from).unwrap_or(0);
+        match self.conf.event {
+            HookEvent::TempAbove => Some(gpu.temp > threshold),
+            HookEvent::TempBelow => Some(gpu.temp < threshold),
+            HookEvent::FanStalled => Some(!stalled(gpu).is_empty()),
+            _ => None,
+        }
+    }
+
+    /// Whether the command of the last run has not finished yet; reaps it
+    /// otherwise
+    fn is_running(&mut self) -> bool {
+        let running = match self.child {
+            Some(ref mut child) => matches!(child.try_wait(), Ok(None)),
+            None => false,
+        };
+        if !running {
+            self.child = None;
+        }
+        running
+    }
+
+    fn is_due(&mut self, previous: Option<&GPUData>, gpu: &GPUData, error: Option<&str>,
+              now: Instant) -> bool {
+        match self.condition(gpu) {
+            Some(true) => {
+                let since = *self.since.get_or_insert(now);
+                if !self.fired && now.duration_since(since) >= Duration::from_secs(self.conf.debounce) {
+                    self.fired = true;
+                    true
+                } else {
+                    false
+                }
+            },
+            Some(false) => {
+                self.since = None;
+                self.fired = false;
+                false
+            },
+            None => occurred(self.conf.event, previous, gpu, error),
+        }
+    }
+}
+
+impl Hooks {
+
+    pub fn new(confs: Vec<HookConf>) -> Hooks {
+        Hooks { hooks: confs.into_iter().map(HookState::new).collect(), previous: None }
+    }
+
+    /// Checks the events of all hooks against the latest state of the
+    /// controlled GPU and runs the commands of those that are due. `error`
+    /// is the error of the last update, if any. Returns the indices of the
+    /// hooks that were started.
+    pub fn update(&mut self, gpu: &GPUData, error: Option<&str>, now: Instant) -> Vec<usize> {
+        let mut started = Vec::new();
+        let previous = self.previous.as_ref();
+
+        for (i, hook) in self.hooks.iter_mut().enumerate() {
+            let running = hook.is_running();
+
+            if !hook.is_due(previous, gpu, error, now) {
+                continue;
+            }
+
+            let min_interval = Duration::from_secs(hook.conf.min_interval);
+            if running || hook.last_run.is_some_and(|t| now.duration_since(t) < min_interval) {
+                hook.suppressed += 1;
+                debug!("Hook {} ({}) suppressed", i + 1, event_name(hook.conf.event));
+                continue;
+            }
+
+            let env = environment(&hook.conf, hook.suppressed, previous, gpu, error);
+            let spawned = Command::new(&hook.conf.command[0])
+                .args(&hook.conf.command[1..])
+                .envs(env)
+                .stdin(Stdio::null())
+                .spawn();
+
+            // Failures are rate limited too, so a broken hook does not flood
+            // the log
+            hook.last_run = Some(now);
+            match spawned {
+                Ok(child) => {
+                    debug!("Hook {} ({}) started", i + 1, event_name(hook.conf.event));
+                    hook.child = Some(child);
+                    hook.suppressed = 0;
+                    started.push(i);
+                },
+                Err(e) => warn!("Could not run hook \"{}\": {}", hook.conf.command[0], e),
+            }
+        }
+
+        self.previous = Some(gpu.clone());
+        started
+    }
+}
+
+#[test]
+fn test_hook_environment() {
+    use status::make_test_status;
+
+    let status = make_test_status();
+    let previous = status.gpus[0].clone();
+    let gpu = status.controlled().unwrap();
+    let conf = HookConf::new(HookEvent::TempAbove, vec!["true".to_string()]).with_threshold(50);
+
+    let env = environment(&conf, 2, Some(&previous), gpu, Some("No such GPU"));
+    let var = |name: &str| env.iter().find(|&(k, _)| k == name).map(|(_, v)| v.as_str());
+
+    assert_eq!(var("NVFC_EVENT"), Some("temp_above"));
+    assert_eq!(var("NVFC_GPU"), Some("1"));
+    assert_eq!(var("NVFC_TEMP"), Some("55"));
+    assert_eq!(var("NVFC_THRESHOLD"), Some("50"));
+    assert_eq!(var("NVFC_SPEEDS"), Some("40,41"));
+    assert_eq!(var("NVFC_RPMS"), Some("1200,1250"));
+    assert_eq!(var("NVFC_MODE"), Some("manual"));
+    assert_eq!(var("NVFC_PREVIOUS_MODE"), Some("auto"));
+    assert_eq!(var("NVFC_PROFILE"), Some("silent"));
+    assert_eq!(var("NVFC_PREVIOUS_PROFILE"), None);
+    assert_eq!(var("NVFC_SUPPRESSED"), Some("2"));
+    assert_eq!(var("NVFC_ERROR"), Some("No such GPU"));
+    assert_eq!(var("NVFC_STALLED"), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hook_fan_stalled() {
+    use status::make_test_status;
+
+    let mut gpu = make_test_status().controlled().unwrap().clone();
+    let conf = HookConf::new(HookEvent::FanStalled, vec!["true".to_string()]).with_min_interval(0);
+    let mut hooks = Hooks::new(vec![conf.clone()]);
+    let t0 = Instant::now();
+
+    // Only faults reported by the health checks count, not a cooler that
+    // has not spun up yet
+    gpu.coolers[0].rpm = 0;
+    assert!(hooks.update(&gpu, None, t0).is_empty());
+
+    gpu.coolers[0].fault = Some(FanFault::Stalled);
+    assert_eq!(hooks.update(&gpu, None, t0), vec![0]);
+    let env = environment(&conf, 0, None, &gpu, None);
+    let var = |name: &str| env.iter().find(|&(k, _)| k == name).map(|(_, v)| v.as_str());
+    assert_eq!(var("NVFC_STALLED"), Some("2"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hook_debounce() {
+    use status::make_test_status;
+
+    let mut gpu = make_test_status().controlled().unwrap().clone();
+    let hot = HookConf::new(HookEvent::TempAbove, vec!["true".to_string()])
+        .with_threshold(80).with_debounce(5).with_min_interval(0);
+    let cool = HookConf::new(HookEvent::TempBelow, vec!["true".to_string()])
+        .with_threshold(60).with_min_interval(0);
+    let mut hooks = Hooks::new(vec![hot, cool]);
+    let t0 = Instant::now();
+    let at = |s: u64| t0 + Duration::from_secs(s);
+
+    // Already below the threshold on startup
+    assert!(hooks.update(&gpu, None, at(0)).is_empty());
+
+    gpu.temp = 85;
+    assert!(hooks.update(&gpu, None, at(2)).is_empty());
+    // Flapping restarts the debounce period
+    gpu.temp = 79;
+    assert!(hooks.update(&gpu, None, at(4)).is_empty());
+    gpu.temp = 85;
+    assert!(hooks.update(&gpu, None, at(6)).is_empty());
+    assert_eq!(hooks.update(&gpu, None, at(11)), vec![0]);
+    // Only once while the condition holds
+    assert!(hooks.update(&gpu, None, at(20)).is_empty());
+
+    gpu.temp = 55;
+    assert_eq!(hooks.update(&gpu, None, at(22)), vec![1]);
+    assert!(hooks.update(&gpu, None, at(24)).is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hook_rate_limit() {
+    use status::make_test_status;
+
+    let mut gpu = make_test_status().controlled().unwrap().clone();
+    let changed = HookConf::new(HookEvent::ModeChanged, vec!["true".to_string()])
+        .with_min_interval(60);
+    let error = HookConf::new(HookEvent::UpdateError, vec!["sleep".to_string(), "10".to_string()])
+        .with_min_interval(0);
+    let mut hooks = Hooks::new(vec![changed, error]);
+    let t0 = Instant::now();
+    let at = |s: u64| t0 + Duration::from_secs(s);
+
+    assert!(hooks.update(&gpu, None, at(0)).is_empty());
+
+    gpu.mode = Some(NVCtrlFanControlState::Auto);
+    assert_eq!(hooks.update(&gpu, Some("lost control"), at(2)), vec![0, 1]);
+    gpu.mode = Some(NVCtrlFanControlState::Manual);
+    assert!(hooks.update(&gpu, Some("lost control"), at(4)).is_empty());
+    assert_eq!(hooks.hooks[0].suppressed, 1);
+    // The previous command is still running
+    assert_eq!(hooks.hooks[1].suppressed, 1);
+
+    // Not suppressed by the first command still being about to exit
+    if let Some(ref mut child) = hooks.hooks[0].child {
+        child.wait().unwrap();
+    }
+    gpu.mode = Some(NVCtrlFanControlState::Auto);
+    assert_eq!(hooks.update(&gpu, None, at(70)), vec![0]);
+    assert_eq!(hooks.hooks[0].suppressed, 0);
+
+    hooks.hooks[1].child.as_mut().unwrap().kill().unwrap();
+    hooks.hooks[1].child.as_mut().unwrap().wait().unwrap();
+    assert_eq!(hooks.update(&gpu, Some("lost control"), at(72)), vec![1]);
+    hooks.hooks[1].child.as_mut().unwrap().kill().unwrap();
+    hooks.hooks[1].child.as_mut().unwrap().wait().unwrap();
+}
@@ -0,0 +1,12 @@
+//! Configuration model and fan speed curves of nvfancontrol. These are shared
+//! between the daemon and third party tools that read or generate
+//! configuration files.
+
+#[macro_use] extern crate log;
+#[macro_use] extern crate serde_derive;
+extern crate serde;
+extern crate toml;
+#[cfg(test)] extern crate serde_json;
+
+pub mod config;
+pub mod fanspeedcurve;
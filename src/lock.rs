@@ -0,0 +1,191 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use serde_json;
+
+/// Default directory of the lock and state files; the same for every user so
+/// that instances of different users exclude each other as well
+pub fn default_dir() -> PathBuf {
+    PathBuf::from("/run/nvfancontrol")
+}
+
+/// Describes a failure to access the lock directory; users without write
+/// access to the default one are pointed to `--lock-dir`
+fn access_error(what: String, e: io::Error) -> String {
+    if e.kind() == io::ErrorKind::PermissionDenied {
+        format!("{}: {}; use --lock-dir to choose a writable directory", what, e)
+    } else {
+        format!("{}: {}", what, e)
+    }
+}
+
+/// Fan control state of a GPU recorded while nvfancontrol runs
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct State {
+    pub pid: u32,
+    pub gpu: u32,
+    /// Whether the fans have been put under manual control
+    pub manual: bool,
+}
+
+/// Exclusive control of a GPU by this process.
+///
+/// The lock is an `flock` on `gpu<N>.lock` which is released by the kernel
+/// however the process ends. Next to it `gpu<N>.state` records whether the
+/// fans are under manual control; it is removed on a clean exit, so finding
+/// one while holding the lock means the previous instance died without
+/// handing the fans back to auto.
+pub struct GpuLock {
+    _file: File,
+    state_path: PathBuf,
+    state: State,
+}
+
+fn write_state(path: &Path, state: &State) -> Result<(), String> {
+    // Replace the file atomically so a crash never leaves a truncated state
+    let tmp = path.with_extension("state.tmp");
+    let contents = serde_json::to_string(state).map_err(|e| e.to_string())?;
+    fs::write(&tmp, contents)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| format!("Could not write state file {:?}: {}", path, e))
+}
+
+impl GpuLock {
+
+    /// Takes the lock of GPU `gpu` in `dir`, which is created if missing.
+    /// Fails if another process holds the lock. Also returns the state left
+    /// behind by a previous instance that did not exit cleanly, if any.
+    pub fn acquire(dir: &Path, gpu: u32) -> Result<(GpuLock, Option<State>), String> {
+        fs::create_dir_all(dir)
+            .map_err(|e| access_error(format!("Could not create {:?}", dir), e))?;
+
+        let lock_path = dir.join(format!("gpu{}.lock", gpu));
+        // Not truncated before the lock is held; it holds the pid of the owner
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(&lock_path)
+            .map_err(|e| access_error(format!("Could not open lock file {:?}", lock_path), e))?;
+
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {},
+            Err(e) if e.as_errno() == Some(Errno::EAGAIN) => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(match pid.trim() {
+                    "" => format!("GPU {} is controlled by another instance", gpu),
+                    pid => format!("GPU {} is controlled by another instance (pid {})", gpu, pid),
+                });
+            },
+            Err(e) => return Err(format!("Could not lock {:?}: {}", lock_path, e)),
+        }
+
+        // The pid is informational only; the lock is what counts
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{}", process::id()))
+            .map_err(|e| format!("Could not write lock file {:?}: {}", lock_path, e))?;
+
+        let state_path = dir.join(format!("gpu{}.state", gpu));
+        let stale = match fs::read_to_string(&state_path) {
+            Ok(contents) => match serde_json::from_str::<State>(&contents) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    warn!("Ignoring invalid state file {:?}: {}", state_path, e);
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        // A record of fans left under manual control stays until the GPU has
+        // been recovered, in case this instance dies before doing it
+        let manual = stale.as_ref().is_some_and(|s| s.manual);
+        let state = State { pid: process::id(), gpu, manual };
+        if !manual {
+            write_state(&state_path, &state)?;
+        }
+
+        Ok((GpuLock { _file: file, state_path, state }, stale))
+    }
+
+    /// Records that the fans left under manual control by a previous
+    /// instance have been handed back to auto
+    pub fn recovered(&mut self) -> Result<(), String> {
+        self.state.manual = false;
+        write_state(&self.state_path, &self.state)
+    }
+
+    /// Records whether the fans are under manual control. Must be called
+    /// before switching to manual control and after switching back to auto.
+    pub fn set_manual(&mut self, manual: bool) -> Result<(), String> {
+        if self.state.manual == manual {
+            return Ok(());
+        }
+        // Updated even if writing fails so the error is only reported once
+        self.state.manual = manual;
+        write_state(&self.state_path, &self.state)
+    }
+}
+
+impl Drop for GpuLock {
+
+    fn drop(&mut self) {
+        // Kept if the fans could not be handed back to auto, so that the next
+        // instance does it
+        if !self.state.manual {
+            let _ = fs::remove_file(&self.state_path);
+        }
+    }
+
+}
+
+#[test]
+fn test_gpu_lock() {
+    let dir = ::std::env::temp_dir().join(format!("nvfancontrol-lock-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let (mut lock, stale) = GpuLock::acquire(&dir, 1).unwrap();
+    assert_eq!(stale, None);
+
+    let err = GpuLock::acquire(&dir, 1).err().unwrap();
+    assert_eq!(err, format!("GPU 1 is controlled by another instance (pid {})", process::id()));
+    // Other GPUs are independent
+    assert!(GpuLock::acquire(&dir, 0).is_ok());
+
+    // Fans left under manual control are reported to the next instance
+    lock.set_manual(true).unwrap();
+    drop(lock);
+    let (lock, stale) = GpuLock::acquire(&dir, 1).unwrap();
+    assert_eq!(stale, Some(State { pid: process::id(), gpu: 1, manual: true }));
+
+    // Still reported until the GPU has been recovered
+    drop(lock);
+    let (mut lock, stale) = GpuLock::acquire(&dir, 1).unwrap();
+    assert_eq!(stale.map(|s| s.manual), Some(true));
+    lock.recovered().unwrap();
+    drop(lock);
+    let (mut lock, stale) = GpuLock::acquire(&dir, 1).unwrap();
+    assert_eq!(stale, None);
+
+    lock.set_manual(true).unwrap();
+    lock.set_manual(false).unwrap();
+    drop(lock);
+    assert!(!dir.join("gpu1.state").exists());
+    assert_eq!(GpuLock::acquire(&dir, 1).unwrap().1, None);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_access_error_hint() {
+    let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+    assert!(access_error("Could not create \"/run/nvfancontrol\"".to_string(), denied)
+        .ends_with("; use --lock-dir to choose a writable directory"));
+
+    let missing = io::Error::from(io::ErrorKind::NotFound);
+    assert!(!access_error("Could not open".to_string(), missing).contains("--lock-dir"));
+}
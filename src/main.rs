@@ -1,5 +1,9 @@
+extern crate nvfancontrol;
+use nvfancontrol::{config, fanspeedcurve};
+
 extern crate nvctrl;
-use nvctrl::{NvFanController, NvidiaControl, NVCtrlFanControlState};
+use nvctrl::{NvFanController, NvidiaControl, NVCtrlFanControlState, ThermalThresholds};
+use nvctrl::version::{DriverVersion, ROOT_COOLER_CONTROL};
 
 #[macro_use] extern crate log;
 use log::{Log, Record, LevelFilter, Metadata};
@@ -11,33 +15,83 @@ use getopts::Options;
 #[cfg(unix)] extern crate nix;
 #[cfg(unix)] use nix::sys::signal;
 #[cfg(unix)] use std::ffi::OsString;
+#[cfg(all(unix, feature = "dbus-service"))] extern crate dbus;
+#[cfg(all(unix, feature = "dbus-service"))] extern crate dbus_crossroads;
 
 extern crate time;
 extern crate dirs;
+extern crate serde;
+extern crate serde_json;
 
 #[macro_use] extern crate serde_derive;
 
 use std::env;
 use std::thread;
 use std::process;
-use std::time::Duration;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
 use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::io::Write;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::fs;
 use std::path::PathBuf;
-use std::net::{TcpListener, TcpStream, Shutdown};
 
-pub mod config;
-use self::config::{Curve};
+use self::config::{GpuConfig, TomlConf, ProfileConf};
 
 pub mod fanflicker;
-use fanflicker::{FanFlickerFix, FanFlickerRange};
+use fanflicker::FanFlickerFix;
 
-pub mod fanspeedcurve;
 use fanspeedcurve::FanspeedCurve;
 
+pub mod profile;
+use profile::{Profile, Ramp};
+
+pub mod server;
+use server::{Command, Reply, RpcError};
+
+pub mod metrics;
+
+pub mod status;
+use status::{GPUData, Status};
+
+pub mod sample;
+use sample::Sample;
+
+pub mod client;
+
+pub mod hooks;
+use hooks::Hooks;
+
+pub mod health;
+use health::{CoolerSample, FanFault, FanHealth};
+
+pub mod verify;
+use verify::{LevelVerifier, Verdict};
+
+pub mod conflict;
+use conflict::{ConflictEvent, ConflictKind, ConflictPolicy, ConflictTracker, Interference};
+
+pub mod poll;
+use poll::PollScheduler;
+
+#[cfg(unix)]
+pub mod systemd;
+
+#[cfg(unix)]
+pub mod lock;
+
+#[cfg(test)]
+mod fake;
+#[cfg(test)]
+use fake::{FakeController, FakeGpu};
+#[cfg(test)]
+use NVCtrlFanControlState::{Auto, Manual};
+
+#[cfg(all(unix, feature = "dbus-service"))]
+pub mod dbus_service;
+
 const CONF_FILE: &'static str = "nvfancontrol.conf";
-const MIN_VERSION: f32 = 352.09;
+const MIN_VERSION: DriverVersion = DriverVersion::new(352, 9, None);
 const DEFAULT_PORT: u32 = 12125;
 const DEFAULT_CONFIG: &'static str = r"
 [[gpu]]
@@ -46,8 +100,16 @@ enabled = true
 points = [[41, 20], [49, 30], [57, 45], [66, 55], [75, 63], [78, 72], [80, 80]]
 ";
 
+/// Degrees below the slowdown threshold at which the fans are forced to 100%
+const DEFAULT_THROTTLE_MARGIN: u16 = 3;
+
+/// Time between two steps of the ramp down on exit
+const RAMP_DOWN_INTERVAL: Duration = Duration::from_millis(250);
+
 static RUNNING: AtomicBool = AtomicBool::new(false);
-static SRVING: AtomicBool = AtomicBool::new(false);
+static NEXT_PROFILE: AtomicBool = AtomicBool::new(false);
+/// Set by the panic hook; the main thread then restores the fans and exits
+static PANICKED: AtomicBool = AtomicBool::new(false);
 static LOGGER: Logger = Logger;
 
 struct Logger;
@@ -66,113 +128,732 @@ impl Log for Logger {
     fn flush(&self) { }
 }
 
-struct NVFanManager {
+/// What happens to the fans on exit
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RestorePolicy {
+    /// Restore the control mode and levels found on startup
+    Original,
+    /// Hand the fans over to the driver
+    Auto,
+    /// Leave the fans at their current speed
+    Keep,
+}
+
+impl RestorePolicy {
+    fn parse(s: &str) -> Result<RestorePolicy, String> {
+        match s {
+            "original" => Ok(RestorePolicy::Original),
+            "auto" => Ok(RestorePolicy::Auto),
+            "keep" => Ok(RestorePolicy::Keep),
+            _ => Err(format!("Invalid restore policy \"{}\"; expected original, auto or keep", s)),
+        }
+    }
+}
+
+/// Control mode and per cooler levels of a GPU
+struct FanState {
+    mode: NVCtrlFanControlState,
+    levels: Vec<(u32, i32)>,
+}
+
+impl FanState {
+    fn read<C: NvFanController>(ctrl: &C, gpu: u32) -> Result<FanState, String> {
+        let mode = ctrl.get_ctrl_status(gpu)?;
+        let levels = ctrl.gpu_coolers(gpu)?.iter()
+            .map(|c| ctrl.get_fanspeed(gpu, *c).map(|l| (*c, l)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(FanState { mode, levels })
+    }
+}
+
+struct NVFanManager<C: NvFanController = NvidiaControl> {
     gpu: u32,
-    ctrl: NvidiaControl,
-    curve: FanspeedCurve,
+    ctrl: C,
+    /// Adapter name of the GPU
+    name: String,
+    driver: DriverVersion,
+    /// Lower and upper fan speed of the active profile
+    limits: (u16, u16),
+    profiles: Vec<Profile>,
+    active: usize,
     on_time: Option<f64>,
-    force: bool,
     monitor: bool,
     fanflicker: Option<FanFlickerFix>,
+    last_speed: Option<i32>,
+    ramp: Option<Ramp>,
+    /// Speed requested over the control protocol and when it expires
+    manual: Option<(i32, Instant)>,
+    /// Fan handed back to auto over the control protocol
+    released: bool,
+    /// Speed requested by the curve on the last update
+    target: Option<i32>,
+    fanflicker_interventions: u64,
+    update_errors: u64,
+    /// Slowdown and maximum temperature of the GPU, if available
+    thresholds: Option<ThermalThresholds>,
+    /// Degrees below the slowdown threshold at which the fans run at 100%
+    throttle_margin: u16,
+    /// Whether the fans are at 100% to keep the GPU from slowing down
+    near_throttle: bool,
+    /// Levels written to the coolers and the range each one achieves
+    levels: LevelVerifier,
+    health: FanHealth,
+    /// Run all coolers at 100% while one of them has failed
+    failover: bool,
+    /// Changes made to the fans by other programs
+    conflicts: ConflictTracker,
+    /// Reading of the GPU the last update worked on
+    sample: Option<Sample>,
+    /// Control mode as last read or set
+    mode: Option<NVCtrlFanControlState>,
+    /// Requests made to the driver before the last update
+    tick_start: Option<u64>,
+    /// Exclusive control of the GPU; not taken in monitor mode
+    #[cfg(unix)]
+    lock: Option<lock::GpuLock>,
+    /// State of the fans before nvfancontrol took over; not taken in
+    /// monitor mode
+    original: Option<FanState>,
+    restore: RestorePolicy,
+    /// Approach the restored levels gradually
+    ramp_down: bool,
+    /// Set once the fans have been handed back
+    shut_down: bool,
 }
 
-impl Drop for NVFanManager {
+impl<C: NvFanController> Drop for NVFanManager<C> {
 
     fn drop(&mut self) {
-        if !self.monitor {
-            debug!("Resetting fan control");
-            self.reset_fan().unwrap();
-        }
+        // Regular exits call `shutdown` before; this covers early returns
+        self.shutdown();
     }
 
 }
 
-impl NVFanManager {
+/// Refuses a profile whose curve only reaches its highest point beyond the
+/// maximum temperature of the GPU and warns if it lies beyond the slowdown
+/// threshold, where the GPU is already throttled
+fn check_thresholds(profile: &Profile, thresholds: &ThermalThresholds) -> Result<(), String> {
+    let temp = match profile.curve.points().last() {
+        Some(&(t, _)) => t as i32,
+        None => return Ok(())
+    };
+
+    if let Some(max) = thresholds.max.filter(|m| temp >= *m) {
+        return Err(format!("Curve of profile \"{}\" ends at {}°C, at or beyond the maximum \
+                            GPU temperature of {}°C", profile.name, temp, max));
+    }
+    if temp > thresholds.slowdown {
+        warn!("Curve of profile \"{}\" ends at {}°C, beyond the slowdown threshold of {}°C; \
+               the GPU is throttled before the fans reach full speed",
+              profile.name, temp, thresholds.slowdown);
+    }
+    Ok(())
+}
+
+/// Lower and upper fan speed applied for `limits` of a profile
+fn effective_limits(limits: Option<(u16, u16)>) -> (u16, u16) {
+    match limits {
+        Some((low, high)) => (low, high.min(100)),
+        None => (0, 100)
+    }
+}
+
+impl<C: NvFanController> NVFanManager<C> {
     fn new(
+        ctrl: C,
         gpu: u32,
-        curve: FanspeedCurve,
-        force: bool,
+        profiles: Vec<Profile>,
+        active: usize,
         monitor: bool,
-        limits: Option<(u16, u16)>,
-        fanflickerrange: Option<FanFlickerRange>,
-    ) -> Result<NVFanManager, String> {
+        restore: RestorePolicy,
+        ramp_down: bool,
+    ) -> Result<NVFanManager<C>, String> {
 
-        let ctrl = NvidiaControl::new(limits)?;
         let gpu_count = ctrl.gpu_count()?;
-        match ctrl.get_version() {
-            Ok(v) => {
-                validate_driver_version(v)?;
-            }
-            Err(e) => {
-                return Err(format!("Could not get driver version: {}", e))
-            }
-        };
+        let driver = ctrl.get_driver_version()
+            .map_err(|e| format!("Could not get driver version: {}", e))?;
+        validate_driver_version(&driver)?;
 
         if gpu > gpu_count-1 {
             return Err(format!("GPU id {} is not valid; min: 0 max: {}", gpu, gpu_count-1));
         }
 
-        let ret = NVFanManager {
-            gpu: gpu,
-            curve: curve,
-            on_time: None,
-            force: force,
-            monitor: monitor,
-            fanflicker: match fanflickerrange {
-                Some(range) => {
-                    let prev = (range.fickering_starts as i32).max(ctrl.get_fanspeed(0, gpu)?);
-                    Some(FanFlickerFix::new(range, prev))
-                },
-                None => None
+        let thresholds = match ctrl.get_thermal_thresholds(gpu) {
+            Ok(t) => {
+                debug!("Thermal thresholds: {:?}", t);
+                for p in &profiles {
+                    check_thresholds(p, &t)?;
+                }
+                Some(t)
             },
-            ctrl: ctrl,
+            Err(e) => {
+                warn!("Could not read thermal thresholds; throttle protection disabled: {}", e);
+                None
+            }
+        };
+
+        let original = if monitor {
+            None
+        } else {
+            let state = FanState::read(&ctrl, gpu)
+                .map_err(|e| format!("Could not read fan state: {}", e))?;
+            debug!("Fan state on startup: {:?} {:?}", state.mode, state.levels);
+            Some(state)
+        };
+
+        let name = ctrl.get_adapter(gpu)?;
+
+        let mut ret = NVFanManager {
+            gpu,
+            name,
+            driver,
+            limits: effective_limits(profiles[active].limits),
+            profiles,
+            active,
+            on_time: None,
+            monitor,
+            fanflicker: None,
+            last_speed: None,
+            ramp: None,
+            manual: None,
+            released: false,
+            target: None,
+            fanflicker_interventions: 0,
+            update_errors: 0,
+            thresholds,
+            throttle_margin: DEFAULT_THROTTLE_MARGIN,
+            near_throttle: false,
+            levels: LevelVerifier::new(),
+            health: FanHealth::new(),
+            failover: false,
+            conflicts: ConflictTracker::new(ConflictPolicy::Log),
+            sample: None,
+            mode: None,
+            tick_start: None,
+            #[cfg(unix)]
+            lock: None,
+            original,
+            restore,
+            ramp_down,
+            shut_down: false,
+            ctrl,
         };
+        ret.fanflicker = ret.make_fanflicker_fix()?;
 
         Ok(ret)
     }
 
-    fn set_fans(&self, speed: i32) -> Result<(), String> {
-        self.ctrl.set_ctrl_type(self.gpu, NVCtrlFanControlState::Manual)?;
-        let coolers = &*self.ctrl.gpu_coolers(self.gpu)?;
-        for c in coolers {
-            self.ctrl.set_fanspeed(self.gpu, *c, speed)?;
+    fn profile(&self) -> &Profile {
+        &self.profiles[self.active]
+    }
+
+    fn make_fanflicker_fix(&self) -> Result<Option<FanFlickerFix>, String> {
+        match self.profile().fanflicker {
+            Some(range) => {
+                let prev = (range.fickering_starts as i32).max(self.ctrl.get_fanspeed(0, self.gpu)?);
+                Ok(Some(FanFlickerFix::new(range, prev)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Activates profile `idx`. If the fan is currently under manual control
+    /// the speed is ramped gradually towards the output of the new curve.
+    fn switch_profile(&mut self, idx: usize) -> Result<(), String> {
+        if idx >= self.profiles.len() {
+            return Err(format!("Profile index {} is not valid; max: {}",
+                               idx, self.profiles.len()-1));
+        }
+
+        if idx == self.active {
+            return Ok(());
+        }
+
+        self.active = idx;
+        self.limits = effective_limits(self.profile().limits);
+        self.fanflicker = self.make_fanflicker_fix()?;
+        self.ramp = self.last_speed.map(Ramp::new);
+
+        info!("Switched to profile \"{}\"", self.profile().name);
+        Ok(())
+    }
+
+    /// Replaces the curve of the active profile
+    fn set_curve(&mut self, points: Vec<(u16, u16)>) -> Result<(), String> {
+        let curve = FanspeedCurve::new(points)?;
+        let profile = self.profile().with_curve(curve)?;
+        if let Some(ref t) = self.thresholds {
+            check_thresholds(&profile, t)?;
+        }
+        self.profiles[self.active] = profile;
+        self.fanflicker = self.make_fanflicker_fix()?;
+        self.ramp = self.last_speed.map(Ramp::new);
+        info!("Curve of profile \"{}\" replaced: {:?}", self.profile().name,
+              self.profile().curve.points());
+        Ok(())
+    }
+
+    /// Holds the fans at `speed` until `timeout` elapses
+    fn set_manual_speed(&mut self, speed: i32, timeout: Duration) -> Result<(), String> {
+        info!("Manual fan speed {}% for {} seconds", speed, timeout.as_secs());
+        self.take_back();
+        self.released = false;
+        self.ramp = None;
+        self.manual = Some((speed, Instant::now() + timeout));
+        self.set_fans(speed)
+    }
+
+    /// Hands the fan back to auto until the curve is resumed
+    fn release_to_auto(&mut self) -> Result<(), String> {
+        info!("Releasing fan to auto control");
+        self.manual = None;
+        self.released = true;
+        self.reset_fan()
+    }
+
+    /// Resumes curve control after `set_manual_speed` or `release_to_auto`
+    fn resume(&mut self) {
+        if self.released || self.manual.is_some() {
+            info!("Resuming curve control");
+        }
+        self.take_back();
+        self.manual = None;
+        self.released = false;
+    }
+
+    fn set_fans(&mut self, speed: i32) -> Result<(), String> {
+        let speed = match self.ramp {
+            Some(ref mut ramp) => {
+                let (speed, done) = ramp.advance(speed);
+                debug!("Ramping to new profile: {}%", speed);
+                if done { self.ramp = None; }
+                speed
+            },
+            None => speed
+        };
+
+        self.record_manual(true);
+        self.manual_control()?;
+        let (low, high) = self.limits;
+        self.write_levels(speed.max(low as i32).min(high as i32))?;
+        self.last_speed = Some(speed);
+        Ok(())
+    }
+
+    /// Sets every cooler to `level`, clipped to the range it achieves.
+    /// Coolers already at that level are left alone.
+    fn write_levels(&mut self, level: i32) -> Result<(), String> {
+        for c in self.ctrl.gpu_coolers(self.gpu)?.iter() {
+            let level = self.levels.clip(*c, level);
+            if self.levels.needs_write(*c, level) {
+                self.ctrl.set_fanspeed(self.gpu, *c, level)?;
+                self.levels.set(*c, level);
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts the fans under manual control unless they already are
+    fn manual_control(&mut self) -> Result<(), String> {
+        if self.mode == Some(NVCtrlFanControlState::Manual) {
+            return Ok(());
+        }
+        self.ctrl.set_ctrl_type(self.gpu, NVCtrlFanControlState::Manual).map_err(|e| {
+            // The most likely cause with these drivers
+            if cfg!(unix) && self.driver.requires_root_for_cooler_control() {
+                format!("{}; drivers >= {} only allow fan control if the X server runs as root",
+                        e, ROOT_COOLER_CONTROL.major)
+            } else {
+                e
+            }
+        })?;
+        self.mode = Some(NVCtrlFanControlState::Manual);
+        Ok(())
+    }
+
+    /// Runs the fans at 100% regardless of the limits of the profile
+    fn set_full_speed(&mut self) -> Result<(), String> {
+        self.ramp = None;
+        self.record_manual(true);
+        self.manual_control()?;
+        self.write_levels(100)?;
+        self.last_speed = Some(100);
+        Ok(())
+    }
+
+    /// Temperatures at which the fan speed changes course: the points of the
+    /// curve and the margin to the slowdown threshold
+    fn turning_points(&self) -> Vec<i32> {
+        let mut points: Vec<i32> = self.profile().curve.points().iter()
+            .map(|&(t, _)| t as i32)
+            .collect();
+        if let Some(t) = self.thresholds {
+            points.push(t.slowdown - self.throttle_margin as i32);
+        }
+        points
+    }
+
+    /// Whether the GPU at `temp` is within `throttle_margin` of its slowdown
+    /// threshold. Once reached, it is only left after the temperature has
+    /// dropped by another margin.
+    fn check_throttle(&mut self, temp: i32) -> bool {
+        let slowdown = match self.thresholds {
+            Some(t) => t.slowdown,
+            None => return false
+        };
+        let margin = self.throttle_margin as i32;
+
+        let near = if self.near_throttle {
+            temp > slowdown - 2 * margin
+        } else {
+            temp >= slowdown - margin
+        };
+
+        if near && !self.near_throttle {
+            warn!("GPU at {}°C is close to its slowdown threshold of {}°C; running fans at 100%",
+                  temp, slowdown);
+        } else if !near && self.near_throttle {
+            info!("GPU cooled down to {}°C; resuming curve control", temp);
+            // Back to the curve gradually
+            self.ramp = Some(Ramp::new(100));
+        }
+        self.near_throttle = near;
+        near
+    }
+
+    fn reset_fan(&mut self) -> Result<(), String> {
+        if self.mode != Some(NVCtrlFanControlState::Auto) {
+            self.ctrl.set_ctrl_type(self.gpu, NVCtrlFanControlState::Auto)?;
+            self.mode = Some(NVCtrlFanControlState::Auto);
+        }
+        self.record_manual(false);
+        self.last_speed = None;
+        self.levels.clear();
+        self.ramp = None;
+        Ok(())
+    }
+
+    /// Applies the restore policy on exit
+    fn restore_fans(&mut self) -> Result<(), String> {
+        if self.conflicts.yielding(Instant::now()) {
+            info!("Leaving fans to the program controlling them");
+            return Ok(());
+        }
+        // May have changed since the last update; always written on exit
+        self.mode = None;
+
+        let levels = match (self.restore, &self.original) {
+            (RestorePolicy::Keep, _) => {
+                info!("Leaving fans at their current speed");
+                // Deliberately left in manual; nothing to recover
+                self.record_manual(false);
+                return Ok(());
+            },
+            (RestorePolicy::Original, &Some(FanState { mode: NVCtrlFanControlState::Manual,
+                                                       ref levels })) => levels.clone(),
+            _ => {
+                debug!("Resetting fan control");
+                return self.reset_fan();
+            }
+        };
+
+        // The original levels are not subject to the limits of the profile
+        info!("Restoring manual fan control at {:?}", levels);
+
+        if let (true, Some(from)) = (self.ramp_down, self.last_speed) {
+            let mut ramps: Vec<(u32, i32, Ramp)> = levels.iter()
+                .map(|&(c, l)| (c, l, Ramp::new(from)))
+                .collect();
+            loop {
+                let mut done = true;
+                for &mut (c, level, ref mut ramp) in ramps.iter_mut() {
+                    let (speed, reached) = ramp.advance(level);
+                    self.ctrl.set_fanspeed(self.gpu, c, speed)?;
+                    done &= reached;
+                }
+                if done {
+                    break;
+                }
+                thread::sleep(RAMP_DOWN_INTERVAL);
+            }
+        }
+
+        self.manual_control()?;
+        for &(c, level) in &levels {
+            self.ctrl.set_fanspeed(self.gpu, c, level)?;
+        }
+        // Restored on purpose; nothing to recover
+        self.record_manual(false);
+        Ok(())
+    }
+
+    /// Hands the fans back according to the restore policy and falls back to
+    /// auto control if that fails, even by panicking. Only the first call
+    /// has an effect.
+    fn shutdown(&mut self) {
+        if self.monitor || self.shut_down {
+            return;
+        }
+        self.shut_down = true;
+
+        match panic::catch_unwind(AssertUnwindSafe(|| self.restore_fans())) {
+            Ok(Ok(())) => return,
+            Ok(Err(e)) => error!("Could not restore fan state: {}", e),
+            Err(_) => error!("Could not restore fan state: panicked"),
+        }
+
+        warn!("Falling back to auto control");
+        let (ctrl, gpu) = (&self.ctrl, self.gpu);
+        match panic::catch_unwind(AssertUnwindSafe(|| {
+            ctrl.set_ctrl_type(gpu, NVCtrlFanControlState::Auto)
+        })) {
+            Ok(Ok(())) => self.record_manual(false),
+            Ok(Err(e)) => error!("Could not reset fan control: {}", e),
+            Err(_) => error!("Could not reset fan control: panicked"),
+        }
+    }
+
+    /// Reads every cooler of the GPU, sets those again that do not run at
+    /// the requested level and reports coolers that fail or recover
+    fn check_coolers(&mut self, sample: &Sample, now: Instant) {
+        if sample.mode != NVCtrlFanControlState::Manual {
+            // Taken over by the driver; nothing to verify
+            self.levels.clear();
+        }
+
+        let mut samples = Vec::with_capacity(sample.coolers.len());
+        for c in &sample.coolers {
+            if let Some(l) = c.level {
+                self.verify_level(c.id, l);
+            }
+            samples.push(CoolerSample {
+                id: c.id,
+                commanded: self.levels.requested(c.id),
+                level: c.level,
+                rpm: c.rpm,
+            });
+        }
+
+        for (id, fault) in self.health.update(&samples, now) {
+            let level = self.levels.requested(id).unwrap_or(0);
+            match fault {
+                Some(FanFault::Stalled) =>
+                    warn!("Fan failure: cooler {} reports 0 RPM at {}%", id, level),
+                Some(FanFault::LowRpm) =>
+                    warn!("Fan failure: cooler {} spins far slower than expected at {}%", id, level),
+                Some(FanFault::SensorDropout) =>
+                    warn!("Fan failure: the speed of cooler {} cannot be read", id),
+                None => info!("Cooler {} recovered", id),
+            }
+            if fault.is_some() && self.failover && !self.monitor {
+                warn!("Running all coolers at 100%");
+            }
+        }
+    }
+
+    /// Applies the conflict policy if another program changed the control
+    /// mode or the levels of the coolers found in `sample` since the last
+    /// update
+    fn check_conflicts(&mut self, sample: &Sample, now: Instant) -> Result<(), String> {
+        if self.monitor || self.conflicts.yielding(now) {
+            return Ok(());
+        }
+        if self.conflicts.resume(now) {
+            info!("Taking back fan control");
+        }
+
+        let found = self.find_interference(sample);
+        let event = match self.conflicts.observe(found.clone(), now) {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+        let found = match (event, found) {
+            (ConflictEvent::Ended, _) | (_, None) => {
+                info!("Fan control no longer contested");
+                return Ok(());
+            },
+            (_, Some(f)) => f,
+        };
+
+        let msg = match found.kind {
+            ConflictKind::Mode => "Another program changed the fan control mode".to_string(),
+            ConflictKind::Level => format!("Another program changed the speed of cooler(s) {}",
+                found.coolers.iter().map(|c| c.to_string()).collect::<Vec<String>>().join(", ")),
+        };
+        match self.conflicts.policy() {
+            ConflictPolicy::Yield(time) => {
+                warn!("{}; leaving the fans to it for {} minutes", msg, time.as_secs() / 60);
+                self.levels.clear();
+                self.ramp = None;
+                // Not ours to recover if this process dies meanwhile
+                self.record_manual(false);
+            },
+            ConflictPolicy::Reassert => {
+                if event == ConflictEvent::Started { warn!("{}; setting it again", msg) }
+                else { debug!("{}; setting it again", msg) }
+                // The levels follow with the update
+                for c in found.coolers.iter() {
+                    self.levels.reassert(*c);
+                }
+                self.manual_control()?;
+            },
+            ConflictPolicy::Log => {
+                if event == ConflictEvent::Started { warn!("{}", msg) } else { debug!("{}", msg) }
+                // Not fought over; only a new speed from the curve is written
+                for c in found.coolers.iter() {
+                    self.levels.leave(*c);
+                }
+            },
         }
         Ok(())
     }
 
-    fn reset_fan(&self) -> Result<(), String> {
-        self.ctrl.set_ctrl_type(self.gpu, NVCtrlFanControlState::Auto)?;
+    /// What has been changed by someone else since the levels were last
+    /// written and verified
+    fn find_interference(&self, sample: &Sample) -> Option<Interference> {
+        if sample.coolers.iter().all(|c| self.levels.requested(c.id).is_none()) {
+            // Nothing written that could have been changed
+            return None;
+        }
+
+        if sample.mode != NVCtrlFanControlState::Manual {
+            return Some(Interference { kind: ConflictKind::Mode, coolers: Vec::new() });
+        }
+
+        let changed: Vec<u32> = sample.coolers.iter()
+            .filter(|c| c.level.is_some_and(|l| self.levels.changed(c.id, l)))
+            .map(|c| c.id)
+            .collect();
+        if changed.is_empty() {
+            None
+        } else {
+            Some(Interference { kind: ConflictKind::Level, coolers: changed })
+        }
+    }
+
+    /// Takes the fans back early from another program, e.g. on a command
+    fn take_back(&mut self) {
+        if self.conflicts.take_back() {
+            info!("Taking back fan control");
+        }
+    }
+
+    /// Compares the `reported` level of cooler `id` to the requested one
+    fn verify_level(&mut self, id: u32, reported: i32) {
+        match self.levels.check(id, reported) {
+            Verdict::Applied | Verdict::Pending => {},
+            Verdict::Retry { requested, reported, first } => {
+                // Written again by the update
+                let msg = format!("Cooler {} reports {}% instead of {}%; setting it again",
+                                  id, reported, requested);
+                if first { warn!("{}", msg) } else { debug!("{}", msg) }
+            },
+            Verdict::Limited { requested, reported, range: (low, high) } => {
+                warn!("Cooler {} stays at {}% when set to {}%; limiting it to {}-{}%",
+                      id, reported, requested, low, high);
+            },
+        }
+    }
+
+    /// Records the control mode in the state file so that the fans can be
+    /// recovered if this process dies
+    #[cfg(unix)]
+    fn record_manual(&mut self, manual: bool) {
+        if let Some(ref mut lock) = self.lock {
+            if let Err(e) = lock.set_manual(manual) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn record_manual(&mut self, _: bool) { }
+
+    /// Takes over `lock`, first handing the fans back to auto if the `stale`
+    /// state of a previous instance says they were left under manual control
+    #[cfg(unix)]
+    fn recover(&mut self, mut lock: lock::GpuLock, stale: Option<lock::State>) -> Result<(), String> {
+        if let Some(state) = stale.filter(|s| s.manual) {
+            warn!("GPU {} was left under manual control by process {} which did not exit \
+                   cleanly; restoring auto control", self.gpu, state.pid);
+            self.ctrl.set_ctrl_type(self.gpu, NVCtrlFanControlState::Auto)
+                .map_err(|e| format!("Could not restore auto control: {}", e))?;
+            lock.recovered()?;
+            info!("Recovered GPU {}", self.gpu);
+            // What preceded the dead instance is unknown
+            self.original = Some(FanState { mode: NVCtrlFanControlState::Auto, levels: Vec::new() });
+        }
+        self.lock = Some(lock);
         Ok(())
     }
 
+    /// Reads the GPU once, checks the reading for other programs changing
+    /// the fans and for failing coolers and updates the fans accordingly
+    fn tick(&mut self, now: Instant) -> Result<(), String> {
+        self.tick_start = self.ctrl.round_trips();
+        // Rather no status of the GPU than an outdated one
+        self.sample = None;
+        let sample = Sample::read(&self.ctrl, self.gpu)?;
+        self.mode = Some(sample.mode);
+
+        if let Err(e) = self.check_conflicts(&sample, now) {
+            debug!("Could not handle another program controlling the fans: {}", e);
+        }
+        self.check_coolers(&sample, now);
+        self.sample = Some(sample);
+
+        self.update()
+    }
+
+    /// Updates the fans according to the last sample
     fn update(&mut self) -> Result<(), String> {
 
-        if self.monitor {
+        if self.monitor || self.released || self.conflicts.yielding(Instant::now()) {
             return Ok(())
         }
 
-        let temp = self.ctrl.get_temp(self.gpu)? as u16;
-        let ctrl_status = self.ctrl.get_ctrl_status(self.gpu)?;
-        let coolers = &*self.ctrl.gpu_coolers(self.gpu)?;
+        let sample = match self.sample {
+            Some(ref s) => s.clone(),
+            None => return Err("The GPU has not been read yet".to_string()),
+        };
+
+        // Take precedence over both the curve and a manual speed
+        if self.check_throttle(sample.temp) {
+            self.target = Some(100);
+            return self.set_full_speed();
+        }
+        if self.failover && !self.health.faulty().is_empty() {
+            self.target = Some(100);
+            return self.set_full_speed();
+        }
 
-        if coolers.len() == 0 {
-            return Err("No coolers available to adjust".to_string());
+        if let Some((speed, until)) = self.manual {
+            if Instant::now() < until {
+                return self.set_fans(speed);
+            }
+            info!("Manual fan speed expired; resuming curve control");
+            self.manual = None;
         }
 
-        let rpm = self.ctrl.get_fanspeed_rpm(self.gpu, coolers[0])?;
+        let temp = sample.temp as u16;
+        let rpm = match sample.coolers.first() {
+            Some(c) => c.rpm.ok_or_else(|| format!("Could not read the RPM of cooler {}", c.id))?,
+            None => return Err("No coolers available to adjust".to_string()),
+        };
+        let gutil = sample.utilization.get("graphics");
 
-        let utilization = self.ctrl.get_utilization(self.gpu)?;
-        let gutil = utilization.get("graphics");
+        let speed = self.profile().curve.speed_y(temp);
+        let minspeed = self.profile().curve.minspeed();
+        self.target = speed;
 
-        if rpm > 0 && !self.force {
-            if let NVCtrlFanControlState::Auto = ctrl_status {
+        if rpm > 0 && !self.profile().force {
+            if let Some(NVCtrlFanControlState::Auto) = self.mode {
                 debug!("Fan is enabled on auto control; doing nothing");
                 return Ok(());
             };
         }
 
-        let speed = self.curve.speed_y(temp);
-
         match (speed, self.on_time, &mut self.fanflicker) {
             (Some(y), _, None) => {
                 let since_epoch: time::Duration =
@@ -190,7 +871,7 @@ impl NVFanManager {
                 // if utilization can't be retrieved the utilization leg is
                 // always false and ignored
                 if diff < 240.0 || gutil.unwrap_or(&-1) > &25 {
-                    self.set_fans(self.curve.minspeed())
+                    self.set_fans(minspeed)
                 } else {
                     debug!("Grace period expired; turning fan off");
                     self.on_time = None;
@@ -203,14 +884,18 @@ impl NVFanManager {
                 self.reset_fan()
             },
             (Some(y), _, Some(fff)) => {
-                let y = fff.fix_speed(rpm, y);
-                self.set_fans(y)
+                let fixed = fff.fix_speed(rpm, y);
+                if fixed != y {
+                    self.fanflicker_interventions += 1;
+                }
+                self.set_fans(fixed)
             },
             (None, _, Some(fff)) => {
                 // The jump from 0 to some RPM (presumably in the flicker range) will
                 // cause flickering, which will then raise the RPM too much. So keep
                 // it at the lowest speed.
                 debug!("FanFlickerFix: preventing fan-off");
+                self.fanflicker_interventions += 1;
                 let new_speed = fff.fix_speed(rpm, fff.minimum());
                 self.set_fans(new_speed)
             },
@@ -224,12 +909,38 @@ extern fn sigint(_: i32) {
     RUNNING.store(false, Ordering::Relaxed);
 }
 
+#[cfg(unix)]
+extern "C" fn sigusr1(_: i32) {
+    NEXT_PROFILE.store(true, Ordering::Relaxed);
+}
+
 #[cfg(windows)]
 fn sigint() {
     debug!("Interrupt signal");
     RUNNING.store(false, Ordering::Relaxed);
 }
 
+/// Makes a panic on any thread end the main loop, which then hands back the
+/// fans before exiting. The panic sets `panicked` and clears `running`.
+fn install_panic_hook(running: &'static AtomicBool, panicked: &'static AtomicBool) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        panicked.store(true, Ordering::SeqCst);
+        running.store(false, Ordering::SeqCst);
+    }));
+}
+
+/// Runs `body` and hands back the fans of `mgr` afterwards, even if `body`
+/// panics. Returns whether `body` completed.
+fn supervise<C, F>(mgr: &mut NVFanManager<C>, body: F) -> bool
+    where C: NvFanController, F: FnOnce(&mut NVFanManager<C>)
+{
+    let completed = panic::catch_unwind(AssertUnwindSafe(|| body(&mut *mgr))).is_ok();
+    mgr.shutdown();
+    completed
+}
+
 #[cfg(unix)]
 fn register_signal_handlers() -> Result<(), String> {
     let sigaction = signal::SigAction::new(signal::SigHandler::Handler(sigint),
@@ -244,6 +955,14 @@ fn register_signal_handlers() -> Result<(), String> {
             }
         };
     }
+
+    let sigaction = signal::SigAction::new(signal::SigHandler::Handler(sigusr1),
+                                           signal::SaFlags::empty(),
+                                           signal::SigSet::empty());
+    if let Err(err) = unsafe { signal::sigaction(signal::SIGUSR1, &sigaction) } {
+        return Err(format!("Could not register SIG #{:?} handler: {:?}",
+                           signal::SIGUSR1, err));
+    }
     Ok(())
 }
 
@@ -349,6 +1068,10 @@ fn make_options() -> Options {
         "Comma separated lower and upper limits, use 0 to disable,
         default: 20,80", "LOWER,UPPER");
     opts.optopt("g", "gpu", "GPU to adjust; must be >= 0", "GPU");
+    opts.optmulti("c", "curve", "Comma separated TEMP:SPEED curve points;
+                  overrides the configuration file for the GPU selected
+                  with \"-g\". A GPU id prefix must name that GPU",
+                  "[GPU=]TEMP:SPEED,...");
     opts.optflag("p", "print-coolers", "Print available GPUs and coolers");
     opts.optflag("f", "force", "Always use the custom curve even if the fan is
                  already spinning in auto mode");
@@ -356,112 +1079,354 @@ fn make_options() -> Options {
                  mode; just log temperatures and fan speeds");
     opts.optflag("j", "json-output", "Print a json representation of the data
                  to stdout (useful for parsing)");
-    opts.optflagopt("t", "tcp-server", "Print a json representation of the data
-                    over a tcp port. Can be optionally followed by the port
-                    number over which the server will listen for incoming
-                    connections", "PORT");
+    opts.optflagopt("t", "tcp-server", "Serve the json representation of the
+                    data over a tcp port on localhost. Can be optionally
+                    followed by the port number over which the server will
+                    listen for incoming connections", "PORT");
+    opts.optmulti("", "listen", "Address the tcp server listens on; implies a
+                  tcp server. IPv6 addresses are enclosed in brackets,
+                  e.g. [::1]:12125. Can be given multiple times", "ADDR:PORT");
+    opts.optflag("", "tcp-control", "Also accept commands that change the fans
+                 over tcp. Anyone who can connect to the server can then
+                 control the fans; prefer the unix socket");
+    opts.optflagopt("", "metrics", "Serve Prometheus metrics at /metrics and a
+                    health check at /healthz over HTTP. Can be optionally
+                    followed by the address to listen on, default: localhost:12126",
+                    "ADDR:PORT");
     opts.optopt("r", "fanflicker", "Range in which fan flicker is prevented,
                      specify as with \"-l\". Also makes fan spin with at
                      least the specified lower limit which must not be zero.",
                      "LOWER,UPPER");
+    opts.optopt("", "restore", "What to do with the fans on exit: original
+                (the control mode and speed found on startup), auto or keep
+                (leave them at the current speed), default: original", "POLICY");
+    opts.optflag("", "ramp-down", "Lower the fan speed gradually when restoring
+                 a manual speed on exit");
+    opts.optflag("", "fan-failure-max", "Run all coolers at 100% while one of
+                 them is stalled, much slower than usual or cannot be read");
+    opts.optopt("", "on-conflict", "What to do when another program changes the
+                fans: yield[:MINUTES] (leave the fans to it, 5 minutes unless
+                given), reassert or log, default: log", "POLICY");
+    opts.optopt("", "interval", "Time between two updates in seconds; either
+                fixed or MIN,MAX to update faster while the temperature
+                changes quickly and slower while it is stable,
+                default: 1,5", "SECONDS");
+    opts.optopt("", "throttle-margin", "Run the fans at 100% once the GPU is
+                within this many degrees of its slowdown threshold,
+                default: 3", "DEGREES");
+    opts.optopt("P", "profile", "Profile to activate on startup; overrides the
+                profile of the GPU section in the configuration file. On
+                Unix send SIGUSR1 to switch to the next profile", "NAME");
+    #[cfg(unix)] {
+        opts.optflagopt("u", "unix-socket", "Accept control commands over a unix
+                        domain socket. Can be optionally followed by the path of
+                        the socket, default: $XDG_RUNTIME_DIR/nvfancontrol.sock
+                        or /run/nvfancontrol.sock", "PATH");
+        opts.optopt("", "socket-owner", "Owner of the control socket", "USER");
+        opts.optopt("", "socket-group", "Group of the control socket", "GROUP");
+        opts.optopt("", "socket-mode", "Octal permissions of the control socket,
+                    default: 666", "MODE");
+        opts.optmulti("", "socket-allow-user", "User allowed to modify the fan
+                      state over the control socket in addition to root and
+                      the user running nvfancontrol. Can be given multiple
+                      times", "USER");
+        opts.optmulti("", "socket-allow-group", "Primary group allowed to modify
+                      the fan state over the control socket. Can be given
+                      multiple times", "GROUP");
+        opts.optopt("", "lock-dir", "Directory of the lock and state files that
+                    prevent several instances from controlling the same GPU,
+                    default: /run/nvfancontrol", "DIR");
+    }
+    #[cfg(all(unix, feature = "dbus-service"))]
+    opts.optflagopt("", "dbus", "Expose the status and control methods as
+                    org.nvfancontrol.Daemon on D-Bus. Can be optionally
+                    followed by the bus to use: system or session,
+                    default: system", "BUS");
     opts.optflag("h", "help", "Print this help message");
 
     opts
 }
 
+#[cfg(unix)]
+fn make_socket_permissions(matches: &getopts::Matches) -> Result<server::SocketPermissions, String> {
+    let mode = match matches.opt_str("socket-mode") {
+        Some(m) => u32::from_str_radix(&m, 8)
+            .map_err(|e| format!("Invalid option for \"--socket-mode\": {}: {}", m, e))?,
+        None => 0o666
+    };
+
+    let owner = match matches.opt_str("socket-owner") {
+        Some(u) => Some(server::parse_user(&u)?),
+        None => None
+    };
+
+    let group = match matches.opt_str("socket-group") {
+        Some(g) => Some(server::parse_group(&g)?),
+        None => None
+    };
+
+    let allowed_uids = matches.opt_strs("socket-allow-user").iter()
+        .map(|u| server::parse_user(u))
+        .collect::<Result<Vec<_>, _>>()?;
+    let allowed_gids = matches.opt_strs("socket-allow-group").iter()
+        .map(|g| server::parse_group(g))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(server::SocketPermissions { owner, group, mode, allowed_uids, allowed_gids })
+}
+
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!("Usage: {0} [options]\n       {0} migrate-config [options] [FILE]\n       \
+                         {0} status [options]",
+                        program);
     println!("{}", opts.usage(&brief));
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct GPUData {
-    timespec: i64,
-    temp: i32,
-    speed: Vec<i32>,
-    rpm: Vec<i32>,
-    load: i32,
-    mode: Option<NVCtrlFanControlState>
-}
+fn make_migrate_options() -> Options {
+    let mut opts = Options::new();
 
-impl GPUData {
-    fn new(mgr: &NVFanManager, gpu: u32) -> Result<GPUData, String> {
+    opts.optflag("i", "in-place", "Replace FILE with the converted configuration;
+                 the original is kept as FILE.bak, which must not exist");
+    opts.optflag("h", "help", "Print this help message");
 
-        let coolers = mgr.ctrl.gpu_coolers(gpu)?;
-        let temp = mgr.ctrl.get_temp(gpu)?;
-        let mut speed: Vec<i32> = Vec::with_capacity(coolers.len());
-        let mut rpm: Vec<i32> = Vec::with_capacity(coolers.len());
+    opts
+}
 
-        for i in 0..coolers.len() {
-            let current_speed = mgr.ctrl.get_fanspeed(gpu, coolers[i])?;
-            let current_rpm = mgr.ctrl.get_fanspeed_rpm(gpu, coolers[i])?;
-            speed.push(current_speed);
-            rpm.push(current_rpm);
-        }
+/// Converts a legacy configuration file to TOML. If no file is given the
+/// configuration file nvfancontrol would load is used.
+fn migrate_config(program: &str, args: &[String]) -> Result<(), String> {
+    let opts = make_migrate_options();
 
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(e) => return Err(format!("Could not parse command line: {:?}", e))
+    };
 
-        Ok(GPUData {
-            timespec: -1,
-            temp: temp,
-            speed: speed,
-            rpm: rpm,
-            load: -1,
-            mode: None
-        })
+    if matches.opt_present("h") {
+        let brief = format!("Usage: {} migrate-config [options] [FILE]", program);
+        println!("{}", opts.usage(&brief));
+        return Ok(());
     }
 
-    fn update_from_mgr(&mut self, timespec: i64, mgr: &NVFanManager, gpu: u32) {
-        self.timespec = timespec;
-        self.temp = mgr.ctrl.get_temp(gpu).unwrap();
-        self.load = match mgr.ctrl.get_utilization(gpu).unwrap().get("graphics") {
-            Some(v) => *v,
-            None => -1
-        };
-        self.mode = mgr.ctrl.get_ctrl_status(gpu).ok();
-        let coolers_ref = mgr.ctrl.gpu_coolers(gpu).unwrap();
-        for i in 0..coolers_ref.len() {
-            self.rpm[i] = mgr.ctrl.get_fanspeed_rpm(gpu, coolers_ref[i]).unwrap();
-            self.speed[i] = mgr.ctrl.get_fanspeed(gpu, coolers_ref[i]).unwrap();
+    let path = match matches.free.first() {
+        Some(p) => PathBuf::from(p),
+        None => match find_config_file() {
+            Some(p) => p,
+            None => return Err("No config file found".to_string())
         }
+    };
 
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+    let migration = config::migrate_legacy_string(&contents)
+        .map_err(|e| format!("Could not convert {:?}: {}", path, e))?;
+
+    for line in &migration.skipped {
+        warn!("Skipped {}", line);
     }
-}
 
-fn serve_tcp(data: Arc<RwLock<GPUData>>, port: u32) {
-    let l = TcpListener::bind(format!(":::{}", port).as_str()).unwrap();
-    SRVING.store(true, Ordering::Relaxed);
-    info!("Spinning up TCP server at {:?}", l.local_addr().unwrap());
-    'server: loop {
-        match l.accept() {
-            Ok((mut s, client)) => {
-                if RUNNING.load(Ordering::Relaxed) {
-                    debug!("Incoming TCP connection: {:?}", client);
-                    let raw_data = data.read().unwrap();
-                    let json = format!("{}\n",
-                                       serde_json::to_string(&*raw_data).unwrap());
-                    s.write_all(json.as_bytes()).ok();
-                } else {
-                    SRVING.store(false, Ordering::Relaxed);
-                    break 'server;
-                }
-                s.shutdown(Shutdown::Both).ok();
-            }
-            Err(e) => {
-                error!("TCP server error: {:?}", e);
-            }
+    if matches.opt_present("i") {
+        let mut backup = path.clone().into_os_string();
+        backup.push(".bak");
+        if PathBuf::from(&backup).exists() {
+            return Err(format!("Backup {:?} already exists; move it out of the way first",
+                               backup));
         }
-    };
-    debug!("TCP server terminated")
+        fs::copy(&path, &backup)
+            .map_err(|e| format!("Could not back up {:?}: {}", path, e))?;
+        fs::write(&path, migration.toml)
+            .map_err(|e| format!("Could not write {:?}: {}", path, e))?;
+        info!("Converted {:?}; original saved as {:?}", path, backup);
+    } else {
+        print!("{}", migration.toml);
+    }
+
+    Ok(())
 }
 
-fn list_gpus_and_coolers() -> Result<(), String> {
-    let ctrl = NvidiaControl::new(None)?;
-    let gpu_count = ctrl.gpu_count()?;
+fn make_status_options() -> Options {
+    let mut opts = Options::new();
 
-    println!("Found {} available GPU(s)", gpu_count);
+    opts.optopt("t", "tcp", "Address of the tcp server of the daemon,
+                default: localhost:12125", "ADDR:PORT");
+    #[cfg(unix)]
+    opts.optopt("u", "unix-socket", "Path of the control socket of the daemon;
+                used by default if it exists", "PATH");
+    opts.optflag("j", "json", "Print the status document as json");
+    opts.optflag("w", "watch", "Keep printing the status after every update");
+    opts.optflag("h", "help", "Print this help message");
 
-    for gpu in 0..gpu_count {
-        let name = ctrl.get_adapter(gpu)?;
+    opts
+}
+
+fn print_status(status: &Status, json: bool) {
+    if json {
+        println!("{}", serde_json::to_string(status).unwrap());
+    } else {
+        print!("{}", client::format_table(status));
+    }
+}
+
+/// Prints the status of a running instance
+fn query_status(program: &str, args: &[String]) -> Result<(), String> {
+    let opts = make_status_options();
+
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(e) => return Err(format!("Could not parse command line: {:?}", e))
+    };
+
+    if matches.opt_present("h") {
+        let brief = format!("Usage: {} status [options]", program);
+        println!("{}", opts.usage(&brief));
+        return Ok(());
+    }
+
+    let tcp = client::Endpoint::Tcp(matches.opt_str("t")
+        .unwrap_or_else(|| format!("localhost:{}", DEFAULT_PORT)));
+
+    #[cfg(unix)]
+    let endpoint = match matches.opt_str("u") {
+        Some(path) => client::Endpoint::Unix(PathBuf::from(path)),
+        None => {
+            let path = server::default_socket_path();
+            if !matches.opt_present("t") && path.exists() {
+                client::Endpoint::Unix(path)
+            } else {
+                tcp
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let endpoint = tcp;
+
+    let mut conn = client::Connection::connect(&endpoint)?;
+    let json = matches.opt_present("j");
+
+    if !matches.opt_present("w") {
+        print_status(&conn.get_status()?, json);
+        return Ok(());
+    }
+
+    conn.subscribe()?;
+    while let Some(status) = conn.next_status()? {
+        if !json {
+            println!();
+        }
+        print_status(&status, json);
+    }
+    Err("Connection closed by daemon".to_string())
+}
+
+/// Reads the status of every GPU; the one managed by `mgr` is flagged as
+/// controlled unless in monitor-only mode and taken from the sample of the
+/// last update
+fn make_status<C: NvFanController>(mgr: &NVFanManager<C>, timespec: i64) -> Result<Status, String> {
+    let mut gpus = Vec::new();
+    for i in 0..mgr.ctrl.gpu_count()? {
+        let mut gpu = match mgr.sample {
+            Some(ref s) if i == mgr.gpu => {
+                let mut gpu = GPUData::from_sample(i, mgr.name.clone(), s, mgr.thresholds);
+                // Possibly changed by the update
+                gpu.mode = mgr.mode;
+                gpu
+            },
+            _ => GPUData::read(&mgr.ctrl, i),
+        };
+        if i == mgr.gpu {
+            gpu.controlled = !mgr.monitor;
+            gpu.target = mgr.target;
+            gpu.profile = Some(mgr.profile().name.clone());
+            gpu.fanflicker_interventions = mgr.fanflicker_interventions;
+            gpu.update_errors = mgr.update_errors;
+            for c in gpu.coolers.iter_mut() {
+                c.fault = mgr.health.fault(c.id);
+            }
+            gpu.conflict = mgr.conflicts.status(Instant::now());
+        }
+        gpus.push(gpu);
+    }
+
+    let round_trips = mgr.tick_start.and_then(|s| mgr.ctrl.round_trips().map(|n| n - s));
+    if let Some(gpu) = gpus.iter_mut().find(|g| g.index == mgr.gpu) {
+        gpu.round_trips = round_trips;
+    }
+    Ok(Status::new(timespec, gpus))
+}
+
+#[derive(Serialize)]
+struct ControlConfig {
+    gpu: u32,
+    monitor: bool,
+    profile: String,
+    profiles: Vec<ProfileConf>,
+    manual_speed: Option<i32>,
+    released: bool,
+}
+
+/// Executes a command received over the control protocol
+fn handle_command<C: NvFanController>(mgr: &mut NVFanManager<C>, data: &RwLock<Status>, command: Command) -> Reply {
+    let control_error = |e: String| RpcError::new(server::CONTROL_ERROR, e);
+
+    match command {
+        Command::GetStatus => {
+            let raw_data = data.read().unwrap();
+            return Ok(serde_json::to_value(&*raw_data).unwrap());
+        },
+        Command::GetConfig => {
+            let conf = ControlConfig {
+                gpu: mgr.gpu,
+                monitor: mgr.monitor,
+                profile: mgr.profile().name.clone(),
+                profiles: mgr.profiles.iter().map(|p| p.to_conf()).collect(),
+                manual_speed: mgr.manual.map(|(speed, _)| speed),
+                released: mgr.released,
+            };
+            return Ok(serde_json::to_value(&conf).unwrap());
+        },
+        _ => {}
+    }
+
+    if mgr.monitor {
+        return Err(RpcError::new(server::NOT_PERMITTED, "fan control disabled (monitor-only)"));
+    }
+
+    match command {
+        Command::SetProfile(name) => {
+            let idx = match mgr.profiles.iter().position(|p| p.name == name) {
+                Some(idx) => idx,
+                None => return Err(RpcError::new(server::INVALID_PARAMS,
+                                                 format!("unknown profile \"{}\"", name)))
+            };
+            mgr.resume();
+            mgr.switch_profile(idx).map_err(control_error)?;
+            mgr.update().map_err(control_error)?;
+        },
+        Command::SetCurve(points) => {
+            mgr.set_curve(points).map_err(|e| RpcError::new(server::INVALID_PARAMS, e))?;
+            mgr.resume();
+            mgr.update().map_err(control_error)?;
+        },
+        Command::SetManualSpeed { speed, timeout } => {
+            mgr.set_manual_speed(speed as i32, timeout).map_err(control_error)?;
+        },
+        Command::ReleaseToAuto => {
+            mgr.release_to_auto().map_err(control_error)?;
+        },
+        Command::GetStatus | Command::GetConfig | Command::Subscribe => unreachable!(),
+    }
+
+    Ok(serde_json::Value::Null)
+}
+
+fn list_gpus_and_coolers() -> Result<(), String> {
+    let ctrl = NvidiaControl::new(None)?;
+    let gpu_count = ctrl.gpu_count()?;
+
+    println!("Found {} available GPU(s)", gpu_count);
+
+    for gpu in 0..gpu_count {
+        let name = ctrl.get_adapter(gpu)?;
         println!("GPU #{}: {} ", gpu, name);
         let coolers = &*ctrl.gpu_coolers(gpu)?;
         for c in coolers {
@@ -473,12 +1438,12 @@ fn list_gpus_and_coolers() -> Result<(), String> {
 
 }
 
-fn make_default_curve(gpu: u32) -> Vec<(u16, u16)> {
+fn make_default_config(gpu: u32) -> GpuConfig<TomlConf> {
     let conf = DEFAULT_CONFIG.replace("{}", &gpu.to_string());
     let c = config::from_string(&conf).unwrap();
     debug!("Default configuration loaded");
     debug!("{}", conf.trim());
-    c.points(gpu as usize).to_vec()
+    c.into_toml()
 }
 
 fn validate_gpu_id(gpu: u32) -> Result<(), String> {
@@ -492,32 +1457,12 @@ fn validate_gpu_id(gpu: u32) -> Result<(), String> {
     }
 }
 
-fn validate_driver_version(version: String) -> Result<(), String> {
-    let parts: Vec<&str> = version.split(".").collect();
-
-    let major = parts[0];
-    let minor: &str;
-    if parts.len() < 2 {
-        minor = "00";
-    } else {
-        minor = parts[1];
-    }
-
-    let version_str = format!("{}.{}", major, minor);
-    let version_num = version_str.parse::<f32>();
-
-    if version_num.is_err() {
-        return Err("Could not parse driver version".to_string());
-    }
-
-    if version_num.unwrap() < MIN_VERSION {
-        let err = format!("Unsupported driver version; need >= {:.2}",
-                          MIN_VERSION);
-        return Err(err);
+fn validate_driver_version(version: &DriverVersion) -> Result<(), String> {
+    if *version < MIN_VERSION {
+        return Err(format!("Unsupported driver version {}; need >= {}", version, MIN_VERSION));
     }
 
     Ok(())
-
 }
 
 trait ProcessOrDefault<T> {
@@ -543,6 +1488,27 @@ impl<T> ProcessOrDefault<T> for getopts::Matches {
 pub fn main() {
 
     let args: Vec<String> = env::args().collect();
+
+    if args.get(1).map(|s| s.as_str()) == Some("migrate-config") {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Info);
+        if let Err(e) = migrate_config(&args[0], &args[2..]) {
+            error!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    if args.get(1).map(|s| s.as_str()) == Some("status") {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Info);
+        if let Err(e) = query_status(&args[0], &args[2..]) {
+            error!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
     let opts = make_options();
 
     let matches = match opts.parse(&args[1..]) {
@@ -572,23 +1538,9 @@ pub fn main() {
     log::set_logger(&LOGGER).unwrap();
     log::set_max_level(log_level);
 
-    let force_update = matches.opt_present("f");
-
-    let limits = matches.opt_process_or_default(
-        "l",
-        |arg: &str| {
-            match parse_ascending_arg_pair("l", arg) {
-                Ok(lims) => lims,
-                Err(e) => {
-                    error!("{}", e);
-                    process::exit(1);
-                }
-            }
-        },
-        // Default limits
-        Some((20, 80))
-    );
+    install_panic_hook(&RUNNING, &PANICKED);
 
+    let force_update = matches.opt_present("f");
 
     let gpu = matches.opt_process_or_default(
         "g",
@@ -618,38 +1570,106 @@ pub fn main() {
         }
     }
 
-    let mut fanflicker = None;
-
-    let points: Vec<(u16, u16)> = match find_config_file() {
+    let mut conf: GpuConfig<TomlConf> = match find_config_file() {
         Some(path) => {
             info!("Loading configuration file: {:?}", path);
             match config::from_file(path) {
                 Ok(c) => {
-                    fanflicker = c.fanflicker(gpu as usize);
-                    c.points(gpu as usize).to_vec()
+                    let legacy = if let config::Config::Legacy(_) = c {
+                        warn!("The legacy configuration format is deprecated; \
+                               convert it with \"{} migrate-config\"", args[0]);
+                        true
+                    } else {
+                        false
+                    };
+                    let mut conf = c.into_toml();
+                    // The legacy format applies to whichever GPU is selected
+                    if legacy {
+                        conf.gpus[0].id = gpu;
+                    }
+                    conf
                 }
                 Err(e) => {
                     warn!("{}; using default curve", e);
-                    make_default_curve(gpu)
+                    make_default_config(gpu)
                 }
             }
         },
         None => {
             warn!("No config file found; using default curve");
-            make_default_curve(gpu)
+            make_default_config(gpu)
         }
     };
 
-    debug!("Curve points: {:?}", points);
+    let env_settings = match config::apply_env(&mut conf, env::vars()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Invalid environment: {}", e);
+            process::exit(1);
+        }
+    };
 
-    let curve = match FanspeedCurve::new(points) {
-        Ok(curve) => curve,
-        Err(msg) => {
-            error!("{}", msg.to_string());
+    for arg in matches.opt_strs("c") {
+        let (id, points) = match config::parse_curve(&arg) {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Invalid option for \"--curve\": {}: {}", arg, e);
+                process::exit(1);
+            }
+        };
+        let id = id.unwrap_or(gpu);
+        // Only the selected GPU is controlled
+        if id != gpu {
+            error!("Invalid option for \"--curve\": {}: GPU {} is not controlled; select it \
+                    with \"-g {}\"", arg, id, id);
             process::exit(1);
         }
+
+        if conf.gpu_mut(id).is_none() {
+            conf.gpus.push(TomlConf::new(id));
+        }
+        let gpu_conf = conf.gpu_mut(id).unwrap();
+        gpu_conf.points = points;
+        // An explicit curve takes precedence over the configured profile
+        gpu_conf.profile = None;
+    }
+
+    // Limits given explicitly replace those of the profiles as well
+    let explicit_limits = matches.opt_present("l") || env_settings.limits.is_some();
+    let limits = matches.opt_process_or_default(
+        "l",
+        |arg: &str| {
+            match parse_ascending_arg_pair("l", arg) {
+                Ok(lims) => lims,
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        // From the environment or the default limits
+        env_settings.limits.unwrap_or(Some((20, 80)))
+    );
+
+    let gpu_conf = match conf.gpus.iter().find(|g| g.id == gpu) {
+        Some(g) => g.clone(),
+        None => {
+            warn!("No configuration for GPU {}; using default curve", gpu);
+            make_default_config(gpu).gpus.remove(0)
+        }
     };
 
+    let fanflicker = gpu_conf.fanflicker;
+    let mut profile_confs: Vec<ProfileConf> = conf.profiles;
+    if explicit_limits {
+        for p in &mut profile_confs {
+            p.limits = None;
+        }
+    }
+    let mut hooks = Hooks::new(conf.hooks);
+    let default_profile: Option<String> = gpu_conf.profile;
+    let points: Vec<(u16, u16)> = gpu_conf.points;
+
     let fanflicker = matches.opt_process_or_default(
         "r",
         |arg: &str| {
@@ -665,29 +1685,164 @@ pub fn main() {
         fanflicker
     );
 
-    let fanflickerrange = match fanflicker {
-        Some(range) => match FanFlickerRange::new(range, &curve, &limits) {
-            Ok(range) => Some(range),
+    let mut profiles: Vec<Profile> = Vec::with_capacity(profile_confs.len() + 1);
+
+    // The curve of the GPU section itself is available as the "default" profile
+    if !points.is_empty() {
+        debug!("Curve points: {:?}", points);
+
+        let curve = match FanspeedCurve::new(points) {
+            Ok(curve) => curve,
+            Err(msg) => {
+                error!("{}", msg.to_string());
+                process::exit(1);
+            }
+        };
+
+        match Profile::new(config::DEFAULT_PROFILE, curve, limits, fanflicker, force_update) {
+            Ok(p) => profiles.push(p),
             Err(e) => {
                 error!("{}", e);
                 process::exit(1);
-            },
+            }
         }
-        None => None,
+    }
+
+    for conf in &profile_confs {
+        match Profile::from_conf(conf, limits, fanflicker, force_update) {
+            Ok(p) => profiles.push(p),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let active_profile = match matches.opt_str("P").or(env_settings.profile)
+                                                    .or(default_profile) {
+        Some(name) => match profiles.iter().position(|p| p.name == name) {
+            Some(idx) => idx,
+            None => {
+                error!("Profile \"{}\" is not defined", name);
+                process::exit(1);
+            }
+        },
+        None => 0
     };
 
+    info!("Using profile \"{}\" ({} available)", profiles[active_profile].name,
+          profiles.len());
+
     let monitor_only = matches.opt_present("m");
 
-    let mut mgr = match NVFanManager::new(gpu, curve, force_update, monitor_only, limits, fanflickerrange) {
+    // Service manager integration; the notification socket and sockets
+    // passed by socket activation
+    #[cfg(unix)]
+    let (mut notifier, activated) = match systemd::Notifier::from_env()
+            .and_then(|n| systemd::listen_fds().map(|l| (n, l))) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("systemd: {}", e);
+            process::exit(1);
+        }
+    };
+    #[cfg(unix)]
+    let activated_unix = activated.iter().any(|l| matches!(*l, systemd::Activated::Unix(_)));
+
+    #[cfg(unix)]
+    let socket_perms = if matches.opt_present("u") || activated_unix {
+        match make_socket_permissions(&matches) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    // Refuse to fight over the fans with another instance
+    #[cfg(unix)]
+    let gpu_lock = if monitor_only {
+        None
+    } else {
+        let dir = matches.opt_str("lock-dir").map(PathBuf::from).unwrap_or_else(lock::default_dir);
+        match lock::GpuLock::acquire(&dir, gpu) {
+            Ok(l) => Some(l),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+    };
+
+    let restore = match matches.opt_str("restore") {
+        Some(p) => match RestorePolicy::parse(&p) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        },
+        None => RestorePolicy::Original
+    };
+
+    let ramp_down = matches.opt_present("ramp-down");
+    let throttle_margin = match matches.opt_str("throttle-margin") {
+        Some(m) => match m.parse::<u16>() {
+            Ok(m) => m,
+            Err(e) => {
+                error!("Invalid option for \"--throttle-margin\": {}: {}", m, e);
+                process::exit(1);
+            }
+        },
+        None => DEFAULT_THROTTLE_MARGIN
+    };
+    let conflict_policy = match matches.opt_str("on-conflict") {
+        Some(p) => match ConflictPolicy::parse(&p) {
+            Ok(p) => p,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        },
+        None => ConflictPolicy::Log
+    };
+    let mut scheduler = match matches.opt_str("interval") {
+        Some(i) => match poll::parse_interval(&i) {
+            Ok(i) => PollScheduler::new(i),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        },
+        None => PollScheduler::new(poll::DEFAULT_INTERVAL)
+    };
+    let mut mgr = match NvidiaControl::new(None).and_then(|ctrl| {
+        NVFanManager::new(ctrl, gpu, profiles, active_profile, monitor_only, restore, ramp_down)
+    }) {
         Ok(m) => m,
         Err(s) => {
             error!("{}", s);
             process::exit(1);
         }
     };
+    mgr.throttle_margin = throttle_margin;
+    mgr.failover = matches.opt_present("fan-failure-max");
+    mgr.conflicts = ConflictTracker::new(conflict_policy);
 
-    info!("NVIDIA driver version: {}",
-          mgr.ctrl.get_version().unwrap());
+    #[cfg(unix)] {
+        if let Some((lock, stale)) = gpu_lock {
+            if let Err(e) = mgr.recover(lock, stale) {
+                error!("{}", e);
+                drop(mgr);
+                process::exit(1);
+            }
+        }
+    }
+
+    info!("NVIDIA driver version: {}", mgr.driver);
     let gpu_count = mgr.ctrl.gpu_count().unwrap();
     for i in 0u32..gpu_count {
         info!("NVIDIA graphics adapter #{}: {}", i,
@@ -703,7 +1858,6 @@ pub fn main() {
         };
     }
 
-    let timeout = Duration::new(2, 0);
     RUNNING.store(true, Ordering::Relaxed);
 
     if monitor_only {
@@ -712,11 +1866,22 @@ pub fn main() {
 
     let json_output = matches.opt_present("j");
 
-    let data = Arc::new(RwLock::new(GPUData::new(&mgr, 0).unwrap()));
-
-    let server_port = if matches.opt_present("t") {
-        let srv_data = data.clone();
-        let strport = format!("{}", DEFAULT_PORT);
+    let data = match make_status(&mgr, -1) {
+        Ok(status) => Arc::new(RwLock::new(status)),
+        Err(e) => {
+            error!("Could not read GPU status: {}", e);
+            drop(mgr);
+            process::exit(1);
+        }
+    };
+    let (cmd_tx, cmd_rx) = mpsc::channel::<server::Request>();
+    let subscribers = Arc::new(server::Subscribers::new());
+
+    // Addresses of the TCP server; "-t" and the environment only listen on
+    // localhost
+    let mut listen = matches.opt_strs("listen");
+    if matches.opt_present("t") || (listen.is_empty() && env_settings.port.is_some()) {
+        let strport = format!("{}", env_settings.port.unwrap_or(DEFAULT_PORT));
         let port: u32 = match matches.opt_default("t", strport.as_str()) {
             Some(s) => {
                 match s.parse::<u32>() {
@@ -727,53 +1892,621 @@ pub fn main() {
                     }
                 }
             }
-            None => {
-                warn!("No port provided for server, using default");
-                DEFAULT_PORT
+            // Only enabled through the environment
+            None => match env_settings.port {
+                Some(p) => p,
+                None => {
+                    warn!("No port provided for server, using default");
+                    DEFAULT_PORT
+                }
             }
         };
-        thread::spawn(move || { serve_tcp(srv_data, port) });
-        port
+        listen.push(format!("localhost:{}", port));
+    }
+
+    let mut servers = Vec::new();
+    // Unlike on the unix socket the peers are unknown
+    let tcp_access = if matches.opt_present("tcp-control") {
+        warn!("Accepting control commands over TCP from anyone who can connect");
+        server::Access::Full
     } else {
-        DEFAULT_PORT
+        server::Access::ReadOnly
     };
 
-    // Main loop
-    loop {
+    for addr in &listen {
+        let listeners = match server::bind_tcp(addr) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Could not start TCP server: {}", e);
+                drop(mgr);
+                process::exit(1);
+            }
+        };
+        for listener in listeners {
+            let srv_tx = cmd_tx.clone();
+            let srv_subs = subscribers.clone();
+            servers.push(thread::spawn(move || {
+                server::serve_tcp(listener, srv_tx, srv_subs, &RUNNING, tcp_access)
+            }));
+        }
+    }
+
+    if let Some(addr) = matches.opt_default("metrics", metrics::DEFAULT_METRICS_ADDR) {
+        let listeners = match server::bind_tcp(&addr) {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Could not start metrics server: {}", e);
+                drop(mgr);
+                process::exit(1);
+            }
+        };
+        let timeout = metrics::health_timeout(scheduler.max());
+        for listener in listeners {
+            let metrics_data = data.clone();
+            servers.push(thread::spawn(move || {
+                metrics::serve(listener, metrics_data, timeout, &RUNNING)
+            }));
+        }
+    }
+
+    #[cfg(all(unix, feature = "dbus-service"))] {
+        if let Some(bus) = matches.opt_default("dbus", "system") {
+            let conn = match dbus_service::Bus::parse(&bus).and_then(dbus_service::connect) {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("Could not start D-Bus service: {}", e);
+                    drop(mgr);
+                    process::exit(1);
+                }
+            };
+            let srv_data = data.clone();
+            let srv_tx = cmd_tx.clone();
+            let gpu = mgr.gpu;
+            servers.push(thread::spawn(move || {
+                if let Err(e) = dbus_service::serve(conn, gpu, srv_data, srv_tx, &RUNNING) {
+                    error!("{}", e);
+                }
+            }));
+        }
+    }
+
+    #[cfg(unix)] {
+        for listener in activated {
+            let srv_tx = cmd_tx.clone();
+            let srv_subs = subscribers.clone();
+            match listener {
+                systemd::Activated::Tcp(l) => servers.push(thread::spawn(move || {
+                    server::serve_tcp(l, srv_tx, srv_subs, &RUNNING, tcp_access)
+                })),
+                systemd::Activated::Unix(l) => {
+                    // Always set when a Unix socket has been passed
+                    let perms = socket_perms.clone().unwrap();
+                    servers.push(thread::spawn(move || {
+                        server::serve_unix(l, srv_tx, srv_subs, &RUNNING, perms)
+                    }));
+                }
+            }
+        }
+    }
+
+    // A socket passed by the service manager replaces the one of "-u"
+    #[cfg(unix)]
+    let socket_path = match socket_perms {
+        Some(perms) if !activated_unix => {
+            let path = matches.opt_str("u").map(PathBuf::from)
+                                           .unwrap_or_else(server::default_socket_path);
+            match server::bind_unix(&path, &perms) {
+                Ok(listener) => {
+                    let srv_tx = cmd_tx.clone();
+                    let srv_subs = subscribers.clone();
+                    servers.push(thread::spawn(move || {
+                        server::serve_unix(listener, srv_tx, srv_subs, &RUNNING, perms)
+                    }));
+                    Some(path)
+                },
+                Err(e) => {
+                    error!("{}", e);
+                    drop(mgr);
+                    process::exit(1);
+                }
+            }
+        },
+        _ => None
+    };
+
+    #[cfg(unix)] {
+        if let Some(ref n) = notifier {
+            if let Some(interval) = n.watchdog_interval() {
+                info!("Watchdog enabled; timeout {:?}", interval);
+                if interval < scheduler.max() * 2 {
+                    warn!("Watchdog timeout is shorter than two update intervals ({:?})",
+                          scheduler.max() * 2);
+                }
+            }
+            if let Err(e) = n.ready() {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    // Main loop; the fans are handed back as soon as it ends, also by a panic
+    let completed = supervise(&mut mgr, |mgr| loop {
         if !RUNNING.load(Ordering::Relaxed) {
             debug!("Exiting");
             break;
         }
 
-        if let Err(e) = mgr.update() {
+        if NEXT_PROFILE.swap(false, Ordering::Relaxed) {
+            let next = (mgr.active + 1) % mgr.profiles.len();
+            if let Err(e) = mgr.switch_profile(next) {
+                error!("Could not switch profile: {}", e)
+            }
+        }
+
+        let update_error = mgr.tick(Instant::now()).err();
+        if let Some(ref e) = update_error {
+            mgr.update_errors += 1;
             error!("Could not update fan speed: {}", e)
         };
 
-        let mut raw_data = data.write().unwrap();
         let since_epoch: time::Duration =
                 time::OffsetDateTime::now_utc() - time::OffsetDateTime::UNIX_EPOCH;
-        (*raw_data).update_from_mgr(since_epoch.whole_seconds(), &mgr, 0);
-        drop(raw_data);
+        match make_status(mgr, since_epoch.whole_seconds()) {
+            Ok(status) => *data.write().unwrap() = status,
+            Err(e) => error!("Could not read GPU status: {}", e)
+        };
 
         let raw_data = data.read().unwrap();
-        debug!("Temp: {}; Speed: {:?} RPM ({:?}%); Load: {}%; Mode: {}",
-            raw_data.temp, raw_data.rpm, raw_data.speed, raw_data.load,
-            match raw_data.mode {
-                Some(NVCtrlFanControlState::Auto) => "Auto",
-                Some(NVCtrlFanControlState::Manual) => "Manual",
-                None => "ERR"
-            });
+        if let Some(gpu) = raw_data.gpus.iter().find(|g| g.index == mgr.gpu) {
+            debug!("Temp: {}; Speed: {:?} RPM ({:?}%); Load: {}%; Mode: {}",
+                gpu.temp,
+                gpu.coolers.iter().map(|c| c.rpm).collect::<Vec<i32>>(),
+                gpu.coolers.iter().map(|c| c.speed).collect::<Vec<i32>>(),
+                gpu.load,
+                match gpu.mode {
+                    Some(NVCtrlFanControlState::Auto) => "Auto",
+                    Some(NVCtrlFanControlState::Manual) => "Manual",
+                    None => "ERR"
+                });
+            if let Some(n) = gpu.round_trips {
+                debug!("Requests to the driver: {}", n);
+            }
+            hooks.update(gpu, update_error.as_deref(), Instant::now());
+
+            #[cfg(unix)] {
+                if let Some(ref mut n) = notifier {
+                    let mut status = format!("Temp: {}°C; Speed: {}%; Mode: {}; Profile: {}",
+                        gpu.temp,
+                        gpu.coolers.iter().map(|c| c.speed.to_string())
+                            .collect::<Vec<String>>().join(","),
+                        gpu.mode.map(|m| format!("{:?}", m)).unwrap_or_else(|| "ERR".to_string()),
+                        gpu.profile.as_deref().unwrap_or("-"));
+                    if let Some(d) = gpu.throttle_distance {
+                        status.push_str(&format!("; Throttle in: {}°C", d));
+                    }
+                    if let Err(e) = n.status(&status) {
+                        debug!("{}", e);
+                    }
+                }
+            }
+        }
+
+        // The watchdog is only fed as long as the main loop makes progress
+        #[cfg(unix)] {
+            if let Some(ref mut n) = notifier {
+                if let Err(e) = n.watchdog(Instant::now()) {
+                    debug!("{}", e);
+                }
+            }
+        }
 
         if json_output {
             println!("{}", serde_json::to_string(&*raw_data).unwrap());
         }
+        subscribers.publish(&*raw_data);
+        drop(raw_data);
+
+        // Serve control requests until the next update is due
+        let now = Instant::now();
+        let interval = match mgr.sample {
+            Some(ref sample) => scheduler.next(now, sample.temp, &mgr.turning_points(),
+                                               mgr.ramp.is_some()),
+            None => scheduler.interval()
+        };
+        debug!("Next update in {:?}", interval);
+        let next_update = now + interval;
+        loop {
+            let now = Instant::now();
+            if now >= next_update || !RUNNING.load(Ordering::Relaxed) {
+                break;
+            }
+            match cmd_rx.recv_timeout(next_update - now) {
+                Ok(req) => {
+                    let reply = handle_command(mgr, &data, req.command);
+                    req.reply.send(reply).ok();
+                },
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    thread::sleep(next_update - now);
+                    break;
+                }
+            }
+        }
+    });
+
+    // Also stops the servers if the main loop ended by a panic
+    RUNNING.store(false, Ordering::Relaxed);
+
+    #[cfg(unix)] {
+        if let Some(ref n) = notifier {
+            let _ = n.stopping();
+        }
+    }
+
+    subscribers.close();
+
+    // Servers notice that RUNNING has been cleared on their own
+    for handle in servers {
+        let _ = handle.join();
+    }
+
+    #[cfg(unix)] {
+        if let Some(path) = socket_path {
+            let _ = fs::remove_file(&path);
+        }
+    }
+
+    if !completed || PANICKED.load(Ordering::Relaxed) {
+        error!("Exiting after a panic");
+        drop(mgr);
+        process::exit(101);
+    }
+
+}
+
+#[cfg(test)]
+fn make_test_manager(gpu: FakeGpu, restore: RestorePolicy)
+    -> (NVFanManager<FakeController>, FakeController)
+{
+    let ctrl = FakeController::new(gpu);
+    let curve = FanspeedCurve::new(vec![(40, 30), (80, 80)]).unwrap();
+    let profile = Profile::new("default", curve, None, None, true).unwrap();
+    let mgr = NVFanManager::new(ctrl.clone(), 0, vec![profile], 0, false, restore, false).unwrap();
+    (mgr, ctrl)
+}
+
+#[cfg(unix)]
+#[test]
+fn test_crash_recovery() {
+    let dir = ::std::env::temp_dir().join(format!("nvfancontrol-recover-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (mut lock, _) = lock::GpuLock::acquire(&dir, 0).unwrap();
+    lock.set_manual(true).unwrap();
+    drop(lock);
+
+    // The manager cannot be started; the stale state is left to the next try
+    let (lock, stale) = lock::GpuLock::acquire(&dir, 0).unwrap();
+    assert_eq!(stale.as_ref().map(|s| s.manual), Some(true));
+    let ctrl = FakeController::new(FakeGpu::new(60, Manual, vec![(0, 35)]));
+    let curve = FanspeedCurve::new(vec![(40, 30), (80, 80)]).unwrap();
+    let profile = Profile::new("default", curve, None, None, true).unwrap();
+    assert!(NVFanManager::new(ctrl.clone(), 1, vec![profile], 0, false,
+                              RestorePolicy::Original, false).is_err());
+    drop(lock);
+
+    let (lock, stale) = lock::GpuLock::acquire(&dir, 0).unwrap();
+    assert_eq!(stale.as_ref().map(|s| s.manual), Some(true));
+    let (mut mgr, ctrl) = make_test_manager(FakeGpu::new(60, Manual, vec![(0, 35)]),
+                                            RestorePolicy::Original);
+    mgr.recover(lock, stale).unwrap();
+    assert_eq!(ctrl.gpu().mode, Auto);
+    drop(mgr);
+    assert_eq!(lock::GpuLock::acquire(&dir, 0).unwrap().1, None);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_restore_policies() {
+    let cases = [
+        (RestorePolicy::Original, Auto, Auto, None),
+        (RestorePolicy::Original, Manual, Manual, Some(35)),
+        (RestorePolicy::Auto, Manual, Auto, None),
+        (RestorePolicy::Keep, Auto, Manual, Some(55)),
+    ];
+
+    for &(policy, before, after, level) in cases.iter() {
+        let (mut mgr, ctrl) = make_test_manager(FakeGpu::new(60, before, vec![(0, 35)]), policy);
+        mgr.tick(Instant::now()).unwrap();
+        assert_eq!(ctrl.gpu().coolers, vec![(0, 55)]);
+
+        drop(mgr);
+        let gpu = ctrl.gpu();
+        assert_eq!(gpu.mode, after, "{:?}", policy);
+        if let Some(level) = level {
+            assert_eq!(gpu.coolers, vec![(0, level)], "{:?}", policy);
+        }
+    }
+}
+
+#[test]
+fn test_shutdown_after_panic() {
+    let (mut mgr, ctrl) = make_test_manager(FakeGpu::new(60, Auto, vec![(0, 35), (1, 35)]),
+                                            RestorePolicy::Original);
+
+    let completed = supervise(&mut mgr, |mgr| {
+        mgr.tick(Instant::now()).unwrap();
+        assert_eq!(ctrl.gpu().mode, Manual);
+        ctrl.gpu().panic_on = Some("get_temp");
+        let _ = mgr.tick(Instant::now());
+    });
+
+    assert!(!completed);
+    assert_eq!(ctrl.gpu().mode, Auto);
+
+    // The fans are handed back only once
+    let calls = ctrl.gpu().calls.len();
+    drop(mgr);
+    assert_eq!(ctrl.gpu().calls.len(), calls);
+}
+
+#[test]
+fn test_shutdown_falls_back_to_auto() {
+    let (mut mgr, ctrl) = make_test_manager(FakeGpu::new(60, Manual, vec![(0, 35)]),
+                                            RestorePolicy::Original);
+    mgr.tick(Instant::now()).unwrap();
+
+    // Restoring the original speed panics
+    ctrl.gpu().panic_on = Some("set_fanspeed");
+    drop(mgr);
+    assert_eq!(ctrl.gpu().mode, Auto);
+}
+
+#[test]
+fn test_panic_on_other_thread() {
+    let (mut mgr, ctrl) = make_test_manager(FakeGpu::new(60, Auto, vec![(0, 35)]),
+                                            RestorePolicy::Original);
+    // Flags of this test only; the hook is removed again at the end
+    static RUNNING: AtomicBool = AtomicBool::new(true);
+    static PANICKED: AtomicBool = AtomicBool::new(false);
+    install_panic_hook(&RUNNING, &PANICKED);
+
+    let completed = supervise(&mut mgr, |mgr| {
+        mgr.tick(Instant::now()).unwrap();
+        assert_eq!(ctrl.gpu().mode, Manual);
+
+        ctrl.gpu().panic_on = Some("get_adapter");
+        let reader = ctrl.clone();
+        let handle = thread::spawn(move || reader.get_adapter(0));
+
+        while RUNNING.load(Ordering::SeqCst) {
+            mgr.tick(Instant::now()).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(handle.join().is_err());
+    });
 
-        thread::sleep(timeout);
+    drop(panic::take_hook());
+
+    assert!(completed);
+    assert!(PANICKED.load(Ordering::SeqCst));
+    assert_eq!(ctrl.gpu().mode, Auto);
+}
+
+#[test]
+fn test_throttle_protection() {
+    let (mut mgr, ctrl) = make_test_manager(FakeGpu::new(60, Auto, vec![(0, 35), (1, 35)]),
+                                            RestorePolicy::Auto);
+    mgr.limits = (20, 95);
+
+    // Slowdown at 90°C and a margin of 3°C; the upper limit does not apply
+    // while the fans are forced and they ramp down back to the curve
+    let steps = [(60, 55), (86, 80), (87, 100), (85, 100), (84, 95), (84, 90)];
+    for &(temp, speed) in steps.iter() {
+        ctrl.gpu().temp = temp;
+        mgr.tick(Instant::now()).unwrap();
+        assert_eq!(ctrl.gpu().coolers, vec![(0, speed), (1, speed)], "{}°C", temp);
     }
 
-    if SRVING.load(Ordering::Relaxed) {
-        // Flush the server
-        let _ = TcpStream::connect(format!(":::{}", server_port).as_str());
+    // Also overrides a manual speed
+    mgr.set_manual_speed(40, Duration::from_secs(60)).unwrap();
+    ctrl.gpu().temp = 88;
+    mgr.tick(Instant::now()).unwrap();
+    assert_eq!(ctrl.gpu().coolers, vec![(0, 100), (1, 100)]);
+
+    // Without thresholds the curve is followed
+    let mut gpu = FakeGpu::new(88, Auto, vec![(0, 35)]);
+    gpu.thresholds = None;
+    let (mut mgr, ctrl) = make_test_manager(gpu, RestorePolicy::Auto);
+    mgr.tick(Instant::now()).unwrap();
+    assert_eq!(ctrl.gpu().coolers, vec![(0, 80)]);
+}
+
+#[test]
+fn test_curve_beyond_thresholds() {
+    let (mut mgr, _) = make_test_manager(FakeGpu::new(60, Auto, vec![(0, 35)]),
+                                         RestorePolicy::Auto);
+    // Beyond the slowdown threshold only warns
+    assert!(mgr.set_curve(vec![(40, 30), (95, 100)]).is_ok());
+    assert_eq!(mgr.set_curve(vec![(40, 30), (100, 100)]),
+               Err("Curve of profile \"default\" ends at 100°C, at or beyond the maximum GPU \
+                    temperature of 100°C".to_string()));
+    assert_eq!(mgr.profile().curve.points(), &[(40, 30), (95, 100)]);
+}
+
+#[test]
+fn test_driver_version_gating() {
+    let cases = [
+        ("352.08", Some("Unsupported driver version 352.08; need >= 352.09")),
+        ("352.09", None),
+        // Compared as numbers, not as decimal fractions
+        ("352.10", None),
+        ("470.57.02", None),
+        ("470", Some("Could not get driver version: Invalid driver version \"470\": \
+                      expected 2 or 3 components")),
+    ];
+
+    for &(version, err) in cases.iter() {
+        let mut gpu = FakeGpu::new(60, Auto, vec![(0, 35)]);
+        gpu.version = version;
+        let curve = FanspeedCurve::new(vec![(40, 30), (80, 80)]).unwrap();
+        let profile = Profile::new("default", curve, None, None, true).unwrap();
+        let mgr = NVFanManager::new(FakeController::new(gpu), 0, vec![profile], 0, true,
+                                    RestorePolicy::Auto, false);
+        assert_eq!(mgr.err().as_deref(), err, "{}", version);
+    }
+}
+
+#[test]
+fn test_fan_failure() {
+    let mut gpu = FakeGpu::new(60, Auto, vec![(0, 35), (1, 35)]);
+    gpu.stalled = vec![1];
+    let (mut mgr, ctrl) = make_test_manager(gpu, RestorePolicy::Auto);
+    mgr.failover = true;
+    let t0 = Instant::now();
+
+    for &s in [0, 10, 20, 30].iter() {
+        mgr.tick(t0 + Duration::from_secs(s)).unwrap();
+        assert_eq!(ctrl.gpu().coolers, vec![(0, 55), (1, 55)]);
     }
 
+    // Reported 30 seconds after the first reading at 55%; the remaining
+    // cooler makes up for the failed one right away
+    mgr.tick(t0 + Duration::from_secs(40)).unwrap();
+    let status = make_status(&mgr, 0).unwrap();
+    let faults: Vec<Option<FanFault>> = status.gpus[0].coolers.iter().map(|c| c.fault).collect();
+    assert_eq!(faults, vec![None, Some(FanFault::Stalled)]);
+    assert_eq!(ctrl.gpu().coolers, vec![(0, 100), (1, 100)]);
+}
+
+#[test]
+fn test_level_verification() {
+    let mut gpu = FakeGpu::new(60, Auto, vec![(0, 35), (1, 35)]);
+    gpu.level_range = (30, 100);
+    let (mut mgr, ctrl) = make_test_manager(gpu, RestorePolicy::Auto);
+    let t0 = Instant::now();
+
+    let writes = || ctrl.gpu().calls.iter().filter(|c| **c == "set_fanspeed").count();
+
+    mgr.set_manual_speed(20, Duration::from_secs(60)).unwrap();
+    assert_eq!(writes(), 2);
+    let per_tick: Vec<usize> = (0..6).map(|_| {
+        let before = writes();
+        mgr.tick(t0).unwrap();
+        writes() - before
+    }).collect();
+    // Set again once the deviation has persisted for three updates, then
+    // clipped to the learned limit
+    assert_eq!(per_tick, vec![0, 0, 2, 0, 0, 2]);
+
+    // Requests are clipped to the learned range from now on
+    assert_eq!((mgr.levels.requested(0), mgr.levels.requested(1)), (Some(30), Some(30)));
+    mgr.tick(t0).unwrap();
+    assert_eq!(mgr.levels.check(0, 30), Verdict::Applied);
+    assert_eq!(ctrl.gpu().coolers, vec![(0, 30), (1, 30)]);
+    assert_eq!(writes(), 6);
+}
+
+#[test]
+fn test_external_control() {
+    let (mut mgr, ctrl) = make_test_manager(FakeGpu::new(60, Auto, vec![(0, 35), (1, 35)]),
+                                            RestorePolicy::Auto);
+    let t0 = Instant::now();
+    let at = |s: u64| t0 + Duration::from_secs(s);
+    let tick = |mgr: &mut NVFanManager<FakeController>, now| mgr.tick(now).unwrap();
+    let conflict = |mgr: &NVFanManager<FakeController>| {
+        make_status(mgr, 0).unwrap().gpus[0].conflict.clone()
+            .map(|c| (c.kind, c.coolers, c.policy, c.resume_in))
+    };
+
+    // The curve asks for 55%; only logged by default and the level is left
+    // to the other program
+    tick(&mut mgr, at(0));
+    tick(&mut mgr, at(2));
+    assert_eq!(conflict(&mgr), None);
+    ctrl.gpu().coolers[1].1 = 70;
+    for s in [4, 6, 8, 10, 12, 14].iter() {
+        tick(&mut mgr, at(*s));
+        assert_eq!(conflict(&mgr), Some((ConflictKind::Level, vec![1], "log".to_string(), None)));
+        assert_eq!(ctrl.gpu().coolers, vec![(0, 55), (1, 70)]);
+    }
+    assert_eq!(mgr.levels.clip(1, 55), 55);
+
+    // Until the curve asks for another speed
+    ctrl.gpu().temp = 70;
+    tick(&mut mgr, at(16));
+    let level = ctrl.gpu().coolers[0].1;
+    assert!(level > 55);
+    assert_eq!(ctrl.gpu().coolers, vec![(0, level), (1, level)]);
+    tick(&mut mgr, at(18));
+    assert_eq!(conflict(&mgr), None);
+
+    // Set again right away
+    mgr.conflicts = ConflictTracker::new(ConflictPolicy::Reassert);
+    ctrl.gpu().coolers[1].1 = 30;
+    tick(&mut mgr, at(20));
+    assert_eq!(conflict(&mgr).map(|c| c.2), Some("reassert".to_string()));
+    assert_eq!(ctrl.gpu().coolers, vec![(0, level), (1, level)]);
+
+    ctrl.gpu().mode = Auto;
+    tick(&mut mgr, at(22));
+    assert_eq!(ctrl.gpu().mode, Manual);
+    assert_eq!(conflict(&mgr).unwrap().0, ConflictKind::Mode);
+    tick(&mut mgr, at(23));
+    assert_eq!(conflict(&mgr), None);
+
+    // Left alone for a minute
+    mgr.conflicts = ConflictTracker::new(ConflictPolicy::Yield(Duration::from_secs(60)));
+    ctrl.gpu().coolers = vec![(0, 70), (1, 70)];
+    tick(&mut mgr, at(24));
+    let writes = ctrl.gpu().calls.len();
+    tick(&mut mgr, at(54));
+    assert!(ctrl.gpu().calls[writes..].iter().all(|c| *c != "set_fanspeed" && *c != "set_ctrl_type"));
+    assert_eq!(ctrl.gpu().coolers, vec![(0, 70), (1, 70)]);
+    assert_eq!(conflict(&mgr).map(|c| c.2), Some("yield".to_string()));
+
+    tick(&mut mgr, at(85));
+    assert_eq!(conflict(&mgr), None);
+    assert_eq!(ctrl.gpu().coolers, vec![(0, level), (1, level)]);
+}
+
+#[test]
+fn test_round_trips() {
+    let (mut mgr, ctrl) = make_test_manager(FakeGpu::new(60, Auto, vec![(0, 35), (1, 35)]),
+                                            RestorePolicy::Auto);
+    let mut round_trips = |temp| {
+        ctrl.gpu().temp = temp;
+        mgr.tick(Instant::now()).unwrap();
+        make_status(&mgr, 0).unwrap().gpus[0].round_trips
+    };
+
+    // Temperature, mode, level and RPM of each cooler and utilization; the
+    // mode and the levels only when they change
+    assert_eq!(round_trips(60), Some(7 + 1 + 2));
+    assert_eq!(round_trips(60), Some(7));
+    assert_eq!(round_trips(64), Some(7 + 2));
+    assert_eq!(round_trips(64), Some(7));
+    // Held at the lowest speed of the curve for a while
+    assert_eq!(round_trips(30), Some(7 + 2));
+    assert_eq!(round_trips(30), Some(7));
+}
+
+#[test]
+fn test_unreadable_values() {
+    let mut gpu = FakeGpu::new(60, Auto, vec![(0, 35), (1, 35)]);
+    gpu.no_rpm = vec![1];
+    let (mut mgr, _ctrl) = make_test_manager(gpu, RestorePolicy::Auto);
+    let rpms = |mgr: &NVFanManager<FakeController>| {
+        make_status(mgr, 0).unwrap().gpus[0].coolers.iter().map(|c| c.rpm).collect::<Vec<i32>>()
+    };
+
+    // Reported as unknown, both before the first update and from the sample
+    assert_eq!(rpms(&mgr), vec![35 * 30, -1]);
+    let t0 = Instant::now();
+    for s in 0..20 {
+        mgr.tick(t0 + Duration::from_secs(s * 2)).unwrap();
+    }
+    assert_eq!(rpms(&mgr), vec![55 * 30, -1]);
+    // Never readable, so not a failure either
+    assert!(mgr.health.faulty().is_empty());
 }
@@ -0,0 +1,351 @@
+use std::fmt::{Display, Write as FmtWrite};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use nvctrl::NVCtrlFanControlState;
+use time;
+
+use server::accept_connections;
+use status::{GPUData, Status};
+#[cfg(test)] use status::make_test_status;
+
+/// Address of the metrics listener when none is given
+pub const DEFAULT_METRICS_ADDR: &str = "localhost:12126";
+/// Time beyond two update intervals after which `/healthz` fails
+const HEALTH_SLACK: Duration = Duration::from_secs(20);
+/// Clients must send their request within this time
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Content type of the Prometheus text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: &str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", body),
+        }
+    }
+}
+
+/// Escapes a label value as required by the exposition format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\")
+         .replace('"', "\\\"")
+         .replace('\n', "\\n")
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn sample<V: Display>(out: &mut String, name: &str, labels: &[(&str, &str)], value: V) {
+    let labels = labels.iter()
+        .map(|&(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<String>>()
+        .join(",");
+    writeln!(out, "{}{{{}}} {}", name, labels, value).unwrap();
+}
+
+/// Renders `status` in the Prometheus text exposition format
+pub fn render(status: &Status) -> String {
+    let mut out = String::new();
+    let indices: Vec<String> = status.gpus.iter().map(|g| g.index.to_string()).collect();
+    let gpus: Vec<(&GPUData, [(&str, &str); 2])> = status.gpus.iter().zip(indices.iter())
+        .map(|(g, i)| (g, [("gpu", i.as_str()), ("name", g.name.as_str())]))
+        .collect();
+
+    family(&mut out, "nvfancontrol_temperature_celsius", "gauge",
+           "Core temperature of the GPU");
+    for &(gpu, ref base) in &gpus {
+        sample(&mut out, "nvfancontrol_temperature_celsius", base, gpu.temp);
+    }
+
+    family(&mut out, "nvfancontrol_throttle_distance_celsius", "gauge",
+           "Degrees left until the GPU slows down");
+    for &(gpu, ref base) in &gpus {
+        if let Some(distance) = gpu.throttle_distance {
+            sample(&mut out, "nvfancontrol_throttle_distance_celsius", base, distance);
+        }
+    }
+
+    family(&mut out, "nvfancontrol_fan_speed_percent", "gauge",
+           "Current speed of the cooler in percent");
+    for &(gpu, ref base) in &gpus {
+        for c in &gpu.coolers {
+            sample(&mut out, "nvfancontrol_fan_speed_percent",
+                   &[base[0], base[1], ("cooler", &c.id.to_string())], c.speed);
+        }
+    }
+
+    family(&mut out, "nvfancontrol_fan_speed_rpm", "gauge",
+           "Current speed of the cooler in RPM");
+    for &(gpu, ref base) in &gpus {
+        for c in &gpu.coolers {
+            sample(&mut out, "nvfancontrol_fan_speed_rpm",
+                   &[base[0], base[1], ("cooler", &c.id.to_string())], c.rpm);
+        }
+    }
+
+    family(&mut out, "nvfancontrol_fan_fault", "gauge",
+           "Detected failure of the cooler; 1 for the current fault");
+    for &(gpu, ref base) in &gpus {
+        for c in &gpu.coolers {
+            if let Some(fault) = c.fault {
+                sample(&mut out, "nvfancontrol_fan_fault",
+                       &[base[0], base[1], ("cooler", &c.id.to_string()), ("fault", fault.name())], 1);
+            }
+        }
+    }
+
+    family(&mut out, "nvfancontrol_fan_control_conflict", "gauge",
+           "Fan control contested by another program; 1 for what it changed");
+    for &(gpu, ref base) in &gpus {
+        if let Some(ref conflict) = gpu.conflict {
+            sample(&mut out, "nvfancontrol_fan_control_conflict",
+                   &[base[0], base[1], ("kind", conflict.kind.name())], 1);
+        }
+    }
+
+    family(&mut out, "nvfancontrol_target_speed_percent", "gauge",
+           "Fan speed requested by the curve in percent");
+    for &(gpu, ref base) in &gpus {
+        if let Some(target) = gpu.target {
+            sample(&mut out, "nvfancontrol_target_speed_percent", base, target);
+        }
+    }
+
+    family(&mut out, "nvfancontrol_utilization_percent", "gauge",
+           "Utilization of the GPU engines in percent");
+    for &(gpu, ref base) in &gpus {
+        for (kind, value) in &gpu.utilization {
+            sample(&mut out, "nvfancontrol_utilization_percent",
+                   &[base[0], base[1], ("kind", kind)], value);
+        }
+    }
+
+    family(&mut out, "nvfancontrol_control_mode", "gauge",
+           "Fan control mode of the GPU; 1 for the active mode");
+    for &(gpu, ref base) in &gpus {
+        if let Some(mode) = gpu.mode {
+            let manual = match mode {
+                NVCtrlFanControlState::Auto => 0,
+                NVCtrlFanControlState::Manual => 1,
+            };
+            sample(&mut out, "nvfancontrol_control_mode",
+                   &[base[0], base[1], ("mode", "auto")], 1 - manual);
+            sample(&mut out, "nvfancontrol_control_mode",
+                   &[base[0], base[1], ("mode", "manual")], manual);
+        }
+    }
+
+    family(&mut out, "nvfancontrol_controlled", "gauge",
+           "Whether the fans of the GPU are driven by nvfancontrol");
+    for &(gpu, ref base) in &gpus {
+        sample(&mut out, "nvfancontrol_controlled", base, gpu.controlled as u8);
+    }
+
+    family(&mut out, "nvfancontrol_fanflicker_interventions_total", "counter",
+           "Number of times the fan flicker fix adjusted the fan speed");
+    for &(gpu, ref base) in &gpus {
+        sample(&mut out, "nvfancontrol_fanflicker_interventions_total", base,
+               gpu.fanflicker_interventions);
+    }
+
+    family(&mut out, "nvfancontrol_update_errors_total", "counter",
+           "Number of failed fan speed updates");
+    for &(gpu, ref base) in &gpus {
+        sample(&mut out, "nvfancontrol_update_errors_total", base, gpu.update_errors);
+    }
+
+    family(&mut out, "nvfancontrol_round_trips", "gauge",
+           "Requests made to the driver on the last update");
+    for &(gpu, ref base) in &gpus {
+        if let Some(round_trips) = gpu.round_trips {
+            sample(&mut out, "nvfancontrol_round_trips", base, round_trips);
+        }
+    }
+
+    family(&mut out, "nvfancontrol_last_update_timestamp_seconds", "gauge",
+           "Time of the last update since the epoch");
+    writeln!(out, "nvfancontrol_last_update_timestamp_seconds {}", status.timespec).unwrap();
+
+    out
+}
+
+/// Time after which the data count as stale if updates are at most
+/// `max_interval` apart
+pub fn health_timeout(max_interval: Duration) -> Duration {
+    max_interval * 2 + HEALTH_SLACK
+}
+
+/// Returns whether the data have been updated within `timeout`
+fn is_healthy(status: &Status, now: i64, timeout: Duration) -> bool {
+    status.timespec >= 0 && now - status.timespec <= timeout.as_secs_f64().ceil() as i64
+}
+
+/// Builds the response to an HTTP request line
+fn respond(request_line: &str, data: &Status, now: i64, timeout: Duration) -> Response {
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(m), Some(t)) => (m, t),
+        _ => return Response::text("400 Bad Request", "bad request")
+    };
+
+    if method != "GET" && method != "HEAD" {
+        return Response::text("405 Method Not Allowed", "method not allowed");
+    }
+
+    let path = target.split('?').next().unwrap_or(target);
+    match path {
+        "/metrics" => Response {
+            status: "200 OK",
+            content_type: METRICS_CONTENT_TYPE,
+            body: render(data),
+        },
+        "/healthz" => {
+            if is_healthy(data, now, timeout) {
+                Response::text("200 OK", "ok")
+            } else {
+                Response::text("503 Service Unavailable", "stale")
+            }
+        },
+        _ => Response::text("404 Not Found", "not found")
+    }
+}
+
+fn handle_client<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    data: &RwLock<Status>,
+    now: i64,
+    timeout: Duration,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Headers are of no interest but are consumed so that the client does
+    // not see a reset connection
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let response = {
+        let data = data.read().unwrap();
+        respond(&request_line, &data, now, timeout)
+    };
+
+    write!(writer, "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+                    Connection: close\r\n\r\n",
+           response.status, response.content_type, response.body.len())?;
+    if !request_line.starts_with("HEAD ") {
+        writer.write_all(response.body.as_bytes())?;
+    }
+    writer.flush()
+}
+
+fn handle_tcp_client(stream: TcpStream, data: &RwLock<Status>, timeout: Duration)
+    -> io::Result<()>
+{
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    handle_client(&stream, &stream, data, now, timeout)
+}
+
+/// Serves `/metrics` and `/healthz` from `data` until `running` is unset.
+/// `/healthz` fails once the data are older than `timeout`.
+pub fn serve(listener: TcpListener, data: Arc<RwLock<Status>>, timeout: Duration,
+             running: &'static AtomicBool) {
+    match listener.local_addr() {
+        Ok(addr) => info!("Spinning up metrics server at {:?}", addr),
+        Err(_) => info!("Spinning up metrics server")
+    };
+
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("Metrics server error: {:?}", e);
+        return;
+    }
+
+    accept_connections(|| listener.accept().map(|(s, _)| s), running, "Metrics server", |s| {
+        let data = data.clone();
+        thread::spawn(move || {
+            if let Err(e) = s.set_nonblocking(false).and_then(|_| handle_tcp_client(s, &data, timeout)) {
+                debug!("Metrics client error: {}", e);
+            }
+        });
+    });
+    debug!("Metrics server terminated")
+}
+
+#[test]
+fn test_render_metrics() {
+    let out = render(&make_test_status());
+    let labels = "gpu=\"1\",name=\"GeForce \\\"Test\\\" GPU\"";
+
+    assert!(out.contains("# TYPE nvfancontrol_temperature_celsius gauge\n"));
+    assert!(out.contains("nvfancontrol_temperature_celsius{gpu=\"0\",name=\"Other GPU\"} 35\n"));
+    assert!(out.contains(&format!("nvfancontrol_temperature_celsius{{{}}} 55\n", labels)));
+    assert!(out.contains(&format!("nvfancontrol_throttle_distance_celsius{{{}}} 33\n", labels)));
+    assert!(!out.contains("nvfancontrol_throttle_distance_celsius{gpu=\"0\""));
+    assert!(out.contains(&format!("nvfancontrol_fan_speed_percent{{{},cooler=\"3\"}} 41\n", labels)));
+    assert!(out.contains(&format!("nvfancontrol_fan_speed_rpm{{{},cooler=\"2\"}} 1200\n", labels)));
+    assert!(out.contains(&format!("nvfancontrol_target_speed_percent{{{}}} 45\n", labels)));
+    assert!(out.contains(&format!("nvfancontrol_fan_fault{{{},cooler=\"3\",fault=\"low_rpm\"}} 1\n",
+                                  labels)));
+    assert!(!out.contains("nvfancontrol_fan_fault{gpu=\"1\",name=\"GeForce \\\"Test\\\" GPU\",cooler=\"2\""));
+    assert!(out.contains(&format!("nvfancontrol_fan_control_conflict{{{},kind=\"level\"}} 1\n",
+                                  labels)));
+    assert!(!out.contains("nvfancontrol_fan_control_conflict{gpu=\"0\""));
+    assert!(!out.contains("nvfancontrol_target_speed_percent{gpu=\"0\""));
+    assert!(out.contains(&format!("nvfancontrol_utilization_percent{{{},kind=\"memory\"}} 7\n", labels)));
+    assert!(out.contains(&format!("nvfancontrol_control_mode{{{},mode=\"auto\"}} 0\n", labels)));
+    assert!(out.contains(&format!("nvfancontrol_control_mode{{{},mode=\"manual\"}} 1\n", labels)));
+    assert!(out.contains(&format!("nvfancontrol_controlled{{{}}} 1\n", labels)));
+    assert!(out.contains("# TYPE nvfancontrol_update_errors_total counter\n"));
+    assert!(out.contains(&format!("nvfancontrol_round_trips{{{}}} 7\n", labels)));
+    assert!(!out.contains("nvfancontrol_round_trips{gpu=\"0\""));
+    assert!(out.contains(&format!("nvfancontrol_fanflicker_interventions_total{{{}}} 3\n", labels)));
+}
+
+#[test]
+fn test_http_endpoints() {
+    let data = RwLock::new(make_test_status());
+
+    let mut out = Vec::new();
+    let req = "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let timeout = health_timeout(Duration::from_secs(5));
+    handle_client(req.as_bytes(), &mut out, &data, 1010, timeout).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.starts_with("HTTP/1.0 200 OK\r\n"));
+    assert!(out.contains(METRICS_CONTENT_TYPE));
+    assert!(out.ends_with("nvfancontrol_last_update_timestamp_seconds 1000\n"));
+
+    assert_eq!(respond("GET /healthz HTTP/1.1", &data.read().unwrap(), 1030, timeout).status,
+               "200 OK");
+    assert_eq!(respond("GET /healthz HTTP/1.1", &data.read().unwrap(), 1031, timeout).status,
+               "503 Service Unavailable");
+    // Longer intervals between updates are not mistaken for a stale daemon
+    let slow = health_timeout(Duration::from_secs(60));
+    assert_eq!(respond("GET /healthz HTTP/1.1", &data.read().unwrap(), 1100, slow).status,
+               "200 OK");
+    assert_eq!(respond("GET /other HTTP/1.1", &data.read().unwrap(), 1010, timeout).status,
+               "404 Not Found");
+    assert_eq!(respond("POST /metrics HTTP/1.1", &data.read().unwrap(), 1010, timeout).status,
+               "405 Method Not Allowed");
+}
@@ -19,6 +19,9 @@ pub use self::os::windows::*;
 
 pub mod os;
 
+pub mod version;
+pub use version::DriverVersion;
+
 /// All Fan Controller implementations should implement the
 /// NvFanController trait which provides basic functions to monitor
 /// and manipulate the GPU fan.
@@ -70,9 +73,21 @@ pub trait NvFanController {
     /// * `speed` - The target speed (%)
     fn set_fanspeed(&self, gpu: u32, id: u32, speed: i32) -> Result<(), String>;
 
+    /// Returns the temperatures at which the GPU protects itself
+    ///
+    /// **Arguments**
+    ///
+    /// * `gpu` - The GPU id
+    fn get_thermal_thresholds(&self, gpu: u32) -> Result<ThermalThresholds, String>;
+
     /// Returns version of the NVidia driver in use
     fn get_version(&self) -> Result<String, String>;
 
+    /// Returns the parsed version of the NVidia driver in use
+    fn get_driver_version(&self) -> Result<DriverVersion, String> {
+        self.get_version()?.parse()
+    }
+
     /// Returns the name of the graphics adapter in use
     ///
     /// **Arguments**
@@ -108,16 +123,34 @@ pub trait NvFanController {
     ///
     /// * gpu: The GPU id
     fn gpu_coolers(&self, gpu: u32) -> Result<Cow<Vec<u32>>, String>;
+
+    /// Returns the number of requests made to the driver so far, such as X
+    /// round-trips, or `None` if they are not counted
+    fn round_trips(&self) -> Option<u64> {
+        None
+    }
 }
 
 /// `NVCtrlFanControlState` represents the control state of a
 /// GPU fan. This can be either auto or manual.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum NVCtrlFanControlState {
     Auto = 0,
     Manual
 }
 
+/// `ThermalThresholds` holds the temperatures (in degrees Celsius) at which
+/// the GPU protects itself from overheating
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThermalThresholds {
+    /// Temperature above which the GPU slows down its clocks
+    pub slowdown: i32,
+    /// Slowdown temperature as set by the manufacturer
+    pub default_slowdown: i32,
+    /// Highest temperature the GPU is allowed to reach, if known
+    pub max: Option<i32>,
+}
+
 /// Common implementation of `NvidiaControl` which is the only `NvFanController`
 /// implementation so far. The system dependent bits are implemented in the
 /// platform specific subcrates
@@ -1,9 +1,10 @@
 use libc::{c_int, c_char, c_uchar, c_void, c_uint};
 use std::collections::HashMap;
 use std::{mem, ptr, slice};
+use std::cell::Cell;
 use std::ffi::CStr;
 use std::borrow::Cow;
-use ::{NVCtrlFanControlState, NvFanController};
+use ::{NVCtrlFanControlState, NvFanController, ThermalThresholds};
 
 const XNV_OK: i32 = 1;
 
@@ -211,7 +212,9 @@ pub struct NvidiaControl {
     /// Current lower and upper limits
     pub limits: (u16, u16),
     dpy: *mut Display,
-    _gpus: Vec<UnixGPU>
+    _gpus: Vec<UnixGPU>,
+    /// Requests sent to the X server since initialisation
+    round_trips: Cell<u64>,
 }
 
 impl NvidiaControl {
@@ -276,7 +279,8 @@ impl NvidiaControl {
 
         Ok(NvidiaControl{ limits: lim,
                           dpy: dpy,
-                          _gpus: gpus })
+                          _gpus: gpus,
+                          round_trips: Cell::new(0) })
     }
 }
 
@@ -304,6 +308,24 @@ impl NvidiaControl {
         }
     }
 
+    /// Queries the integer attribute `attr` of GPU `gpu`; `name` is only used
+    /// for error messages
+    fn query_gpu_attribute(&self, gpu: u32, attr: CTRL_ATTR, name: &str) -> Result<i32, String> {
+        let mut tmp = -1 as i32;
+        self.round_trip();
+        match unsafe {
+            XNVCTRLQueryTargetAttribute(self.dpy, CTRL_TARGET::GPU, gpu as i32, 0, attr, &mut tmp)
+        } {
+            XNV_OK => Ok(tmp),
+            i => Err(format!("XNVCtrl QueryAttr({}) failed; error {}", name, i))
+        }
+    }
+
+    /// Counts a request to the X server
+    fn round_trip(&self) {
+        self.round_trips.set(self.round_trips.get() + 1);
+    }
+
     fn check_fan_id(&self, id: u32) -> Result<(), String> {
 
         for gpu in &self._gpus {
@@ -325,6 +347,7 @@ impl NvFanController for NvidiaControl {
         self.check_gpu_id(id)?;
 
         let mut tmp = -1 as i32;
+        self.round_trip();
         match unsafe {
             XNVCTRLQueryTargetAttribute(self.dpy, CTRL_TARGET::GPU, id as i32, 0,
                                         CTRL_ATTR::CORE_TEMPERATURE, &mut tmp)
@@ -338,6 +361,10 @@ impl NvFanController for NvidiaControl {
         Ok(self._gpus.len() as u32)
     }
 
+    fn round_trips(&self) -> Option<u64> {
+        Some(self.round_trips.get())
+    }
+
     fn gpu_coolers(&self, gpu: u32) -> Result<Cow<Vec<u32>>, String> {
 
         self.check_gpu_id(gpu)?;
@@ -351,6 +378,7 @@ impl NvFanController for NvidiaControl {
         self.check_gpu_id(gpu)?;
 
         let mut tmp = -1 as i32;
+        self.round_trip();
         match unsafe {
             XNVCTRLQueryTargetAttribute(self.dpy, CTRL_TARGET::GPU, gpu as i32, 0,
                                         CTRL_ATTR::COOLER_MANUAL_CONTROL, &mut tmp)
@@ -370,6 +398,7 @@ impl NvFanController for NvidiaControl {
 
         self.check_gpu_id(gpu)?;
 
+        self.round_trip();
         match unsafe {
             XNVCTRLSetTargetAttributeAndGetStatus(self.dpy, CTRL_TARGET::GPU, gpu as i32, 0,
                                                   CTRL_ATTR::COOLER_MANUAL_CONTROL,
@@ -385,6 +414,7 @@ impl NvFanController for NvidiaControl {
         self.check_fan_id(id)?;
 
         let mut tmp = -1 as i32;
+        self.round_trip();
         match unsafe {
             XNVCTRLQueryTargetAttribute(self.dpy, CTRL_TARGET::COOLER, id as i32, 0,
                                         CTRL_ATTR::THERMAL_COOLER_CURRENT_LEVEL, &mut tmp)} {
@@ -398,6 +428,7 @@ impl NvFanController for NvidiaControl {
         self.check_fan_id(id)?;
 
         let mut tmp = -1 as i32;
+        self.round_trip();
         match unsafe {
             XNVCTRLQueryTargetAttribute(self.dpy, CTRL_TARGET::COOLER, id as i32, 0,
                                         CTRL_ATTR::THERMAL_COOLER_SPEED, &mut tmp)} {
@@ -411,6 +442,7 @@ impl NvFanController for NvidiaControl {
         self.check_fan_id(id)?;
 
         let true_speed = self.true_speed(speed);
+        self.round_trip();
         match unsafe {
             XNVCTRLSetTargetAttributeAndGetStatus(self.dpy, CTRL_TARGET::COOLER, id as i32,
                                                   0, CTRL_ATTR::THERMAL_COOLER_LEVEL,
@@ -421,6 +453,22 @@ impl NvFanController for NvidiaControl {
         }
     }
 
+    fn get_thermal_thresholds(&self, gpu: u32) -> Result<ThermalThresholds, String> {
+
+        self.check_gpu_id(gpu)?;
+
+        Ok(ThermalThresholds {
+            slowdown: self.query_gpu_attribute(gpu, CTRL_ATTR::CORE_THRESHOLD,
+                                               "CORE_THRESHOLD")?,
+            default_slowdown: self.query_gpu_attribute(gpu, CTRL_ATTR::DEFAULT_CORE_THRESHOLD,
+                                                       "DEFAULT_CORE_THRESHOLD")?,
+            // Only used to refuse curves; no reason to give up throttle
+            // protection without it
+            max: self.query_gpu_attribute(gpu, CTRL_ATTR::MAX_CORE_THRESHOLD,
+                                          "MAX_CORE_THRESHOLD").ok(),
+        })
+    }
+
     fn get_version(&self) -> Result<String, String> {
 
         let num_screens = unsafe { XScreenCount(self.dpy) };
@@ -432,6 +480,7 @@ impl NvFanController for NvidiaControl {
         let mut nv_screen = -1;
 
         for i in 0..num_screens {
+            self.round_trip();
             if unsafe { XNVCTRLIsNvScreen(self.dpy, i) == 1 } {
                 nv_screen = i;
                 break
@@ -443,6 +492,7 @@ impl NvFanController for NvidiaControl {
         }
 
         let v: *mut c_char = unsafe { mem::MaybeUninit::uninit().assume_init() };
+        self.round_trip();
         match unsafe {
             XNVCTRLQueryStringAttribute(self.dpy, nv_screen, 0, CTRL_ATTR::NVIDIA_DRIVER_VERSION, &v)
         } {
@@ -459,6 +509,7 @@ impl NvFanController for NvidiaControl {
         self.check_gpu_id(id)?;
 
         let v: *mut c_char = unsafe { mem::MaybeUninit::uninit().assume_init() };
+        self.round_trip();
         match unsafe {
             XNVCTRLQueryTargetStringAttribute(self.dpy, CTRL_TARGET::GPU, id as i32,
                                               0, CTRL_ATTR::PRODUCT_NAME, &v)
@@ -476,8 +527,9 @@ impl NvFanController for NvidiaControl {
         self.check_gpu_id(id)?;
 
         let v: *mut c_char = unsafe { mem::MaybeUninit::uninit().assume_init() };
+        self.round_trip();
         match unsafe {
-            XNVCTRLQueryTargetStringAttribute(self.dpy, CTRL_TARGET::GPU, 0, 0,
+            XNVCTRLQueryTargetStringAttribute(self.dpy, CTRL_TARGET::GPU, id as i32, 0,
                                               CTRL_ATTR::UTILIZATION, &v)
         } {
             XNV_OK => {
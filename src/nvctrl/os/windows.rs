@@ -8,7 +8,7 @@ use std::borrow::Cow;
 use std::collections::HashMap;
 use std::env;
 use libc;
-use ::{NVCtrlFanControlState, NvFanController};
+use ::{NVCtrlFanControlState, NvFanController, ThermalThresholds};
 
 const NVAPI_SHORT_STRING_MAX: usize = 64;
 const NVAPI_MAX_PHYSICAL_GPUS: usize = 64;
@@ -362,6 +362,10 @@ impl NV_GPU_THERMAL_SETTINGS_V2 {
         self.sensors[index as usize].current_temp
     }
 
+    fn max_temp(&self, index: u32) -> i32 {
+        self.sensors[index as usize].default_max_temp
+    }
+
     /*fn target(&self, index: u32) -> NV_THERMAL_TARGET {
         self.sensors[index as usize].target
     }*/
@@ -870,6 +874,25 @@ impl NvFanController for NvidiaControl {
         }
     }
 
+    fn get_thermal_thresholds(&self, gpu: u32) -> Result<ThermalThresholds, String> {
+
+        self.check_gpu_id(gpu)?;
+
+        // NvAPI only reports the default maximum temperature of the sensor,
+        // which is where the GPU starts to slow down
+        let mut thermal = NV_GPU_THERMAL_SETTINGS_V2::new();
+        match unsafe { NvAPI_GPU_GetThermalSettings(self.handles[gpu as usize],
+                                                    0, &mut thermal) }
+        {
+            0 => Ok(ThermalThresholds {
+                slowdown: thermal.max_temp(0),
+                default_slowdown: thermal.max_temp(0),
+                max: None,
+            }),
+            i => Err(format!("NvAPI_GPU_GetThermalSettings() failed; error {}", i))
+        }
+    }
+
     fn get_version(&self) -> Result<String, String> {
         let mut b = NvAPI_ShortString::new();
         let mut v: libc::c_uint = 0;
@@ -0,0 +1,133 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// First driver version that only allows cooler control from an X server
+/// running as root
+pub const ROOT_COOLER_CONTROL: DriverVersion = DriverVersion::new(465, 0, None);
+
+/// `DriverVersion` is the version of the NVIDIA driver in the form
+/// `major.minor` or `major.minor.patch`, e.g. "352.09" or "470.57.02".
+/// Components are compared numerically; a missing patch component counts
+/// as `0`.
+#[derive(Debug, Clone, Copy)]
+pub struct DriverVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: Option<u32>,
+}
+
+impl DriverVersion {
+
+    pub const fn new(major: u32, minor: u32, patch: Option<u32>) -> DriverVersion {
+        DriverVersion { major, minor, patch }
+    }
+
+    fn key(&self) -> (u32, u32, u32) {
+        (self.major, self.minor, self.patch.unwrap_or(0))
+    }
+
+    /// Whether manual fan control requires the X server to run as root
+    pub fn requires_root_for_cooler_control(&self) -> bool {
+        *self >= ROOT_COOLER_CONTROL
+    }
+}
+
+impl PartialEq for DriverVersion {
+    fn eq(&self, other: &DriverVersion) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for DriverVersion { }
+
+impl PartialOrd for DriverVersion {
+    fn partial_cmp(&self, other: &DriverVersion) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DriverVersion {
+    fn cmp(&self, other: &DriverVersion) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl FromStr for DriverVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DriverVersion, String> {
+        let parts = s.trim().split('.')
+            .map(|p| p.parse::<u32>())
+            .collect::<Result<Vec<u32>, _>>()
+            .map_err(|e| format!("Invalid driver version \"{}\": {}", s.trim(), e))?;
+
+        match parts[..] {
+            [major, minor] => Ok(DriverVersion::new(major, minor, None)),
+            [major, minor, patch] => Ok(DriverVersion::new(major, minor, Some(patch))),
+            _ => Err(format!("Invalid driver version \"{}\": expected 2 or 3 components",
+                             s.trim())),
+        }
+    }
+}
+
+impl fmt::Display for DriverVersion {
+    /// Minor and patch are zero padded as in the versions NVIDIA publishes
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:02}", self.major, self.minor)?;
+        if let Some(patch) = self.patch {
+            write!(f, ".{:02}", patch)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_driver_versions() {
+    let cases = [
+        ("352.09", Some((352, 9, None))),
+        ("352.9", Some((352, 9, None))),
+        ("465.89", Some((465, 89, None))),
+        ("470.57.02", Some((470, 57, Some(2)))),
+        ("535.183.01", Some((535, 183, Some(1)))),
+        ("550.54.14\n", Some((550, 54, Some(14)))),
+        ("470", None),
+        ("470.57.02.1", None),
+        ("470.x", None),
+        ("", None),
+    ];
+
+    for &(s, expected) in cases.iter() {
+        let parsed = s.parse::<DriverVersion>().ok().map(|v| (v.major, v.minor, v.patch));
+        assert_eq!(parsed, expected, "{:?}", s);
+    }
+}
+
+#[test]
+fn test_order_driver_versions() {
+    let cases = [
+        ("352.9", "352.10", Ordering::Less),
+        ("352.09", "352.9", Ordering::Equal),
+        ("352.09", "352.21", Ordering::Less),
+        ("470.57", "470.57.00", Ordering::Equal),
+        ("470.57.02", "470.57.1", Ordering::Greater),
+        ("470.57.02", "470.63.01", Ordering::Less),
+        ("535.183.01", "550.54.14", Ordering::Less),
+        ("1000.1", "999.99.99", Ordering::Greater),
+    ];
+
+    for &(a, b, expected) in cases.iter() {
+        let (a, b) = (a.parse::<DriverVersion>().unwrap(), b.parse::<DriverVersion>().unwrap());
+        assert_eq!(a.cmp(&b), expected, "{} {}", a, b);
+    }
+
+    assert!(!"460.91.03".parse::<DriverVersion>().unwrap().requires_root_for_cooler_control());
+    assert!("465.19.01".parse::<DriverVersion>().unwrap().requires_root_for_cooler_control());
+}
+
+#[test]
+fn test_display_driver_versions() {
+    for s in ["352.09", "470.57.02", "535.183.01"].iter() {
+        assert_eq!(s.parse::<DriverVersion>().unwrap().to_string(), *s);
+    }
+}
@@ -0,0 +1,190 @@
+use std::time::{Duration, Instant};
+
+/// Shortest and longest time between two updates by default
+pub const DEFAULT_INTERVAL: (Duration, Duration) = (Duration::from_secs(1), Duration::from_secs(5));
+/// Temperature change (°C) the GPU may go through between two updates
+const MAX_STEP: f64 = 2.0;
+/// Smallest step considered close to a point of the curve
+const MIN_STEP: f64 = 0.5;
+/// Weight of a new measurement in the rate of a falling or slowing change
+const RATE_WEIGHT: f64 = 0.5;
+/// Factor by which the interval grows at most from one update to the next
+const MAX_GROWTH: f64 = 1.5;
+/// Rate (°C per second) below which the temperature is considered stable
+const STABLE_RATE: f64 = 0.01;
+
+/// Parses `SECONDS` for a fixed interval or `MIN,MAX`; fractions of a second
+/// are allowed
+pub fn parse_interval(s: &str) -> Result<(Duration, Duration), String> {
+    let parse = |v: &str| match v.trim().parse::<f64>() {
+        Ok(secs) if secs > 0.0 && secs.is_finite() => Ok(Duration::from_secs_f64(secs)),
+        _ => Err(format!("Invalid interval \"{}\"; expected seconds > 0", v.trim())),
+    };
+
+    let mut parts = s.splitn(2, ',');
+    let min = parse(parts.next().unwrap_or(""))?;
+    let max = match parts.next() {
+        Some(m) => parse(m)?,
+        None => min,
+    };
+    if max < min {
+        return Err(format!("Invalid interval \"{}\"; the maximum is shorter than the minimum", s));
+    }
+    Ok((min, max))
+}
+
+/// Decides when to update next. The GPU is polled quickly while its
+/// temperature changes fast, more so close to a point where the fan speed
+/// changes course, and increasingly slowly while the temperature is stable.
+/// Time is only taken from the arguments so the schedule is reproducible.
+pub struct PollScheduler {
+    min: Duration,
+    max: Duration,
+    /// Time and temperature of the previous update
+    last: Option<(Instant, i32)>,
+    /// Change of the temperature in °C per second; rises are followed at
+    /// once, anything else is smoothed
+    rate: f64,
+    interval: Duration,
+}
+
+impl PollScheduler {
+
+    pub fn new((min, max): (Duration, Duration)) -> PollScheduler {
+        PollScheduler { min, max, last: None, rate: 0.0, interval: min }
+    }
+
+    /// The longest interval
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The interval chosen last
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Records the temperature `temp` read at `now` and returns the time to
+    /// wait for the next update. `points` are temperatures at which the fan
+    /// speed changes course, such as the points of the curve. The first
+    /// update and any while `busy` are followed by the shortest interval.
+    pub fn next(&mut self, now: Instant, temp: i32, points: &[i32], busy: bool) -> Duration {
+        if let Some((at, prev)) = self.last {
+            let elapsed = now.duration_since(at).as_secs_f64();
+            if elapsed > 0.0 {
+                let rate = (temp - prev) as f64 / elapsed;
+                self.rate = if rate.abs() > self.rate.abs() {
+                    rate
+                } else {
+                    self.rate + RATE_WEIGHT * (rate - self.rate)
+                };
+                if self.rate.abs() < STABLE_RATE {
+                    self.rate = 0.0;
+                }
+            }
+        }
+        let first = self.last.is_none();
+        self.last = Some((now, temp));
+
+        let target = if busy || first {
+            self.min
+        } else if self.rate == 0.0 {
+            self.max
+        } else {
+            // Next point in the direction the temperature is heading
+            let distance = points.iter()
+                .map(|p| (p - temp) as f64 * self.rate.signum())
+                .filter(|d| *d > 0.0)
+                .fold(None, |n: Option<f64>, d| Some(n.map_or(d, |n| n.min(d))));
+            let step = distance.map_or(MAX_STEP, |d| (d / 2.0).clamp(MIN_STEP, MAX_STEP));
+            Duration::from_secs_f64((step / self.rate.abs()).min(self.max.as_secs_f64()))
+        };
+
+        // Shortened at once but only lengthened gradually
+        let grown = self.interval.mul_f64(MAX_GROWTH);
+        self.interval = target.min(grown).clamp(self.min, self.max);
+        self.interval
+    }
+}
+
+#[test]
+fn test_parse_intervals() {
+    let ms = Duration::from_millis;
+    let cases = [
+        ("2", Some((ms(2000), ms(2000)))),
+        ("0.5,5", Some((ms(500), ms(5000)))),
+        ("1, 10", Some((ms(1000), ms(10000)))),
+        ("3,3", Some((ms(3000), ms(3000)))),
+        ("5,1", None),
+        ("0,5", None),
+        ("-1", None),
+        ("1,", None),
+        ("fast", None),
+    ];
+
+    for &(s, expected) in cases.iter() {
+        assert_eq!(parse_interval(s).ok(), expected, "{:?}", s);
+    }
+}
+
+#[test]
+fn test_poll_idle_and_spike() {
+    let mut poll = PollScheduler::new(DEFAULT_INTERVAL);
+    let points = [40, 50, 60, 70, 80];
+    let t0 = Instant::now();
+    let mut now = t0;
+    let mut tick = |temp: i32, poll: &mut PollScheduler| {
+        let interval = poll.next(now, temp, &points, false);
+        now += interval;
+        interval.as_millis()
+    };
+
+    // Idle at a stable temperature; slows down gradually
+    let idle: Vec<u128> = (0..6).map(|_| tick(35, &mut poll)).collect();
+    assert_eq!(idle, vec![1000, 1500, 2250, 3375, 5000, 5000]);
+
+    // Load spike of 2°C per second; back to the shortest interval at once
+    assert_eq!(tick(45, &mut poll), 1000);
+    assert_eq!(tick(47, &mut poll), 1000);
+    // Slowing down close to a point of the curve keeps it there
+    assert_eq!(tick(48, &mut poll), 1000);
+    assert_eq!(tick(49, &mut poll), 1000);
+
+    // Levels off; the rate decays and the interval grows back
+    let settled: Vec<u128> = (0..6).map(|_| tick(49, &mut poll)).collect();
+    assert_eq!(settled, vec![1000, 1500, 2250, 3375, 5000, 5000]);
+}
+
+#[test]
+fn test_poll_near_curve_points() {
+    let t0 = Instant::now();
+    let at = |ms: u64| t0 + Duration::from_millis(ms);
+    let (min, max) = (Duration::from_millis(500), Duration::from_secs(10));
+
+    // Rising at 0.5°C per second; 2°C take 4 seconds
+    let mut far = PollScheduler::new((min, max));
+    far.next(at(0), 30, &[40], false);
+    far.next(at(2000), 31, &[40], false);
+    assert_eq!(far.interval(), Duration::from_millis(750));
+    assert_eq!(far.next(at(4000), 32, &[40], false), Duration::from_millis(1125));
+    assert_eq!(far.next(at(6000), 33, &[40], false), Duration::from_micros(1_687_500));
+
+    // The same 1°C below a point; half a degree takes a second
+    let mut near = PollScheduler::new((min, max));
+    near.next(at(0), 37, &[40], false);
+    near.next(at(2000), 38, &[40], false);
+    assert_eq!(near.next(at(4000), 39, &[40], false), Duration::from_millis(1000));
+    assert_eq!(near.next(at(6000), 40, &[40], false), Duration::from_millis(1500));
+
+    // Falling towards the point from above counts as well
+    let mut falling = PollScheduler::new((min, max));
+    falling.next(at(0), 43, &[40], false);
+    assert_eq!(falling.next(at(2000), 42, &[40], false), Duration::from_millis(750));
+    assert_eq!(falling.next(at(4000), 41, &[40], false), Duration::from_millis(1000));
+
+    // Always the shortest interval while busy, and a fixed one stays fixed
+    assert_eq!(far.next(at(8000), 33, &[40], true), min);
+    let mut fixed = PollScheduler::new((max, max));
+    assert_eq!(fixed.next(at(0), 30, &[], false), max);
+    assert_eq!(fixed.next(at(500), 60, &[], false), max);
+}
@@ -0,0 +1,148 @@
+use config::{ProfileConf, Strategy};
+use fanflicker::FanFlickerRange;
+use fanspeedcurve::FanspeedCurve;
+
+/// Maximum change of fan speed (in %) per update while switching profiles
+const RAMP_STEP: i32 = 5;
+
+/// A validated profile ready to be used by the fan manager
+pub struct Profile {
+    pub name: String,
+    pub curve: FanspeedCurve,
+    pub limits: Option<(u16, u16)>,
+    pub fanflicker: Option<FanFlickerRange>,
+    pub force: bool,
+}
+
+impl Profile {
+
+    /// Builds a profile from its configuration. Limits and fanflicker range
+    /// that are not specified by the profile fall back to `limits` and
+    /// `fanflicker` respectively.
+    pub fn from_conf(
+        conf: &ProfileConf,
+        limits: Option<(u16, u16)>,
+        fanflicker: Option<(u16, u16)>,
+        force: bool,
+    ) -> Result<Profile, String> {
+
+        let curve = FanspeedCurve::new(conf.points.clone())
+            .map_err(|e| format!("profile \"{}\": {}", conf.name, e))?;
+        let limits = conf.limits.or(limits);
+        let force = force || conf.strategy == Strategy::Force;
+
+        Profile::new(&conf.name, curve, limits, conf.fanflicker.or(fanflicker), force)
+            .map_err(|e| format!("profile \"{}\": {}", conf.name, e))
+    }
+
+    pub fn new(
+        name: &str,
+        curve: FanspeedCurve,
+        limits: Option<(u16, u16)>,
+        fanflicker: Option<(u16, u16)>,
+        force: bool,
+    ) -> Result<Profile, String> {
+
+        let fanflicker = match fanflicker {
+            Some(range) => Some(FanFlickerRange::new(range, &curve, &limits)?),
+            None => None,
+        };
+
+        Ok(Profile {
+            name: name.to_string(),
+            curve,
+            limits,
+            fanflicker,
+            force,
+        })
+    }
+
+    /// Returns a copy of this profile using `curve` instead
+    pub fn with_curve(&self, curve: FanspeedCurve) -> Result<Profile, String> {
+        let fanflicker = self.fanflicker
+            .map(|r| (r.minimum_allowed as u16, r.fickering_starts as u16));
+        Profile::new(&self.name, curve, self.limits, fanflicker, self.force)
+    }
+
+    /// Returns the configuration equivalent to this profile
+    pub fn to_conf(&self) -> ProfileConf {
+        ProfileConf {
+            name: self.name.clone(),
+            points: self.curve.points().to_vec(),
+            limits: self.limits,
+            fanflicker: self.fanflicker
+                .map(|r| (r.minimum_allowed as u16, r.fickering_starts as u16)),
+            strategy: if self.force { Strategy::Force } else { Strategy::Yield },
+        }
+    }
+}
+
+/// Gradual transition from the speed set by the previous profile to the
+/// output of the new one
+pub struct Ramp {
+    current: i32,
+}
+
+impl Ramp {
+
+    pub fn new(from: i32) -> Ramp {
+        Ramp { current: from }
+    }
+
+    /// Moves one step towards `target` and returns the speed to apply along
+    /// with whether `target` has been reached
+    pub fn advance(&mut self, target: i32) -> (i32, bool) {
+        let diff = target - self.current;
+        if diff.abs() <= RAMP_STEP {
+            self.current = target;
+        } else {
+            self.current += RAMP_STEP * diff.signum();
+        }
+        (self.current, self.current == target)
+    }
+}
+
+#[test]
+fn test_ramp_up_and_down() {
+    let mut ramp = Ramp::new(30);
+
+    assert_eq!(ramp.advance(50), (35, false));
+    assert_eq!(ramp.advance(50), (40, false));
+    assert_eq!(ramp.advance(42), (42, true));
+
+    let mut ramp = Ramp::new(80);
+    assert_eq!(ramp.advance(70), (75, false));
+    assert_eq!(ramp.advance(60), (70, false));
+    assert_eq!(ramp.advance(72), (72, true));
+}
+
+#[test]
+fn test_profile_from_conf_fallbacks() {
+    let conf = ProfileConf {
+        name: "silent".to_string(),
+        points: vec![(40, 20), (80, 60)],
+        limits: None,
+        fanflicker: None,
+        strategy: Strategy::Force,
+    };
+
+    let p = Profile::from_conf(&conf, Some((20, 80)), None, false).unwrap();
+    assert_eq!(p.name, "silent");
+    assert_eq!(p.limits, Some((20, 80)));
+    assert!(p.fanflicker.is_none());
+    assert!(p.force);
+}
+
+#[test]
+fn test_profile_from_conf_invalid_curve() {
+    let conf = ProfileConf {
+        name: "broken".to_string(),
+        points: vec![(40, 60), (80, 20)],
+        limits: None,
+        fanflicker: None,
+        strategy: Strategy::Yield,
+    };
+
+    let err = Profile::from_conf(&conf, None, None, false).err().unwrap();
+    assert!(err.starts_with("profile \"broken\""));
+}
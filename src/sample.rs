@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+
+use nvctrl::{NvFanController, NVCtrlFanControlState};
+
+/// Reading of a single cooler
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoolerReading {
+    pub id: u32,
+    /// `None` if the level could not be read
+    pub level: Option<i32>,
+    /// `None` if the RPM could not be read
+    pub rpm: Option<i32>,
+}
+
+/// Everything read from the controlled GPU on one update. Adjusting the fans,
+/// the checks of the coolers and the status data all work on the same
+/// sample so that every value is queried from the driver only once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub temp: i32,
+    pub mode: NVCtrlFanControlState,
+    pub coolers: Vec<CoolerReading>,
+    pub utilization: BTreeMap<String, i32>,
+}
+
+impl Sample {
+
+    /// Reads GPU `gpu`. Unreadable coolers are part of the sample; any other
+    /// failure is an error.
+    pub fn read<C: NvFanController>(ctrl: &C, gpu: u32) -> Result<Sample, String> {
+        let temp = ctrl.get_temp(gpu)?;
+        let mode = ctrl.get_ctrl_status(gpu)?;
+        let coolers = ctrl.gpu_coolers(gpu)?.iter()
+            .map(|id| CoolerReading {
+                id: *id,
+                level: ctrl.get_fanspeed(gpu, *id).ok(),
+                rpm: ctrl.get_fanspeed_rpm(gpu, *id).ok(),
+            })
+            .collect();
+        let utilization = ctrl.get_utilization(gpu)?.iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect();
+
+        Ok(Sample { temp, mode, coolers, utilization })
+    }
+
+    /// The level of cooler `id`, if it could be read
+    pub fn level(&self, id: u32) -> Option<i32> {
+        self.coolers.iter().find(|c| c.id == id).and_then(|c| c.level)
+    }
+}
@@ -0,0 +1,861 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)] use std::fs;
+#[cfg(unix)] use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)] use std::os::unix::io::AsRawFd;
+#[cfg(unix)] use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)] use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+#[cfg(unix)] use nix::unistd::{self, Gid, Group, Uid, User};
+
+/// Request is not valid JSON
+pub const PARSE_ERROR: i32 = -32700;
+/// Request is not an object with a `method`
+pub const INVALID_REQUEST: i32 = -32600;
+/// Unknown `method`
+pub const METHOD_NOT_FOUND: i32 = -32601;
+/// Missing or invalid `params`
+pub const INVALID_PARAMS: i32 = -32602;
+/// The fan could not be adjusted
+pub const CONTROL_ERROR: i32 = -32000;
+/// Fan control is disabled (monitor-only mode)
+pub const NOT_PERMITTED: i32 = -32001;
+/// The main loop did not answer in time
+pub const UNAVAILABLE: i32 = -32002;
+
+/// How long a client waits for the main loop to handle a command
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Idle connections are dropped after this long
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(300);
+/// Subscribers that cannot take an update for this long are dropped
+const SUBSCRIBER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum number of concurrent subscribers
+const MAX_SUBSCRIBERS: usize = 64;
+/// How often idle listeners check whether the daemon is shutting down
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Commands accepted by the control protocol
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    GetStatus,
+    GetConfig,
+    SetProfile(String),
+    SetCurve(Vec<(u16, u16)>),
+    SetManualSpeed { speed: u16, timeout: Duration },
+    ReleaseToAuto,
+    /// Handled by the connection itself; never sent to the main loop
+    Subscribe,
+}
+
+impl Command {
+    /// Whether the command only queries the state of the daemon
+    pub fn is_read_only(&self) -> bool {
+        matches!(*self, Command::GetStatus | Command::GetConfig | Command::Subscribe)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new<S: ToString>(code: i32, message: S) -> RpcError {
+        RpcError { code, message: message.to_string() }
+    }
+}
+
+pub type Reply = Result<Value, RpcError>;
+
+/// What a client connection is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    /// All commands are accepted
+    Full,
+    /// Only commands that query the state of the daemon are accepted
+    ReadOnly,
+}
+
+/// A command along with the channel its reply is sent to
+pub struct Request {
+    pub command: Command,
+    pub reply: Sender<Reply>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    /// Updates are pushed to the client after this response
+    #[serde(skip)]
+    subscription: Option<Arc<Subscription>>,
+}
+
+impl Response {
+    fn new(id: Value, reply: Reply) -> Response {
+        match reply {
+            Ok(v) => Response { id, result: Some(v), error: None, subscription: None },
+            Err(e) => Response { id, result: None, error: Some(e), subscription: None },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Notification<'a, T: 'a> {
+    method: &'static str,
+    params: &'a T,
+}
+
+/// The latest update for a single subscriber. Updates that arrive before the
+/// previous one has been written to the client replace it.
+pub struct Subscription {
+    latest: Mutex<Option<Arc<String>>>,
+    ready: Condvar,
+    closed: AtomicBool,
+}
+
+impl Subscription {
+    fn new() -> Subscription {
+        Subscription {
+            latest: Mutex::new(None),
+            ready: Condvar::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    fn push(&self, line: Arc<String>) {
+        *self.latest.lock().unwrap() = Some(line);
+        self.ready.notify_one();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _guard = self.latest.lock().unwrap();
+        self.ready.notify_one();
+    }
+
+    /// Blocks until an update is available; `None` once closed
+    fn next(&self) -> Option<Arc<String>> {
+        let mut latest = self.latest.lock().unwrap();
+        loop {
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            if let Some(line) = latest.take() {
+                return Some(line);
+            }
+            latest = self.ready.wait(latest).unwrap();
+        }
+    }
+}
+
+/// Clients that receive a status update after every tick of the main loop
+pub struct Subscribers {
+    subscriptions: Mutex<Vec<Arc<Subscription>>>,
+}
+
+impl Default for Subscribers {
+    fn default() -> Subscribers {
+        Subscribers::new()
+    }
+}
+
+impl Subscribers {
+    pub fn new() -> Subscribers {
+        Subscribers { subscriptions: Mutex::new(Vec::new()) }
+    }
+
+    fn subscribe(&self) -> Result<Arc<Subscription>, RpcError> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|s| !s.closed.load(Ordering::Relaxed));
+        if subscriptions.len() >= MAX_SUBSCRIBERS {
+            return Err(RpcError::new(UNAVAILABLE, "too many subscribers"));
+        }
+        let subscription = Arc::new(Subscription::new());
+        subscriptions.push(subscription.clone());
+        Ok(subscription)
+    }
+
+    /// Whether there is anyone to publish to
+    pub fn is_empty(&self) -> bool {
+        self.subscriptions.lock().unwrap().is_empty()
+    }
+
+    /// Queues `status` for every subscriber. This never waits on clients;
+    /// slow subscribers only get the most recent update.
+    pub fn publish<T: Serialize>(&self, status: &T) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|s| !s.closed.load(Ordering::Relaxed));
+        if subscriptions.is_empty() {
+            return;
+        }
+
+        let notification = Notification { method: "status", params: status };
+        let line = match serde_json::to_string(&notification) {
+            Ok(json) => Arc::new(format!("{}\n", json)),
+            Err(e) => {
+                error!("Could not serialize status update: {}", e);
+                return;
+            }
+        };
+        for s in subscriptions.iter() {
+            s.push(line.clone());
+        }
+    }
+
+    /// Ends all subscriptions
+    pub fn close(&self) {
+        for s in self.subscriptions.lock().unwrap().drain(..) {
+            s.close();
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProfileParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct CurveParams {
+    points: Vec<(u16, u16)>,
+}
+
+#[derive(Deserialize)]
+struct ManualSpeedParams {
+    speed: u16,
+    /// Seconds after which the curve takes over again
+    timeout: u64,
+}
+
+fn params<T: DeserializeOwned>(req: &Value) -> Result<T, RpcError> {
+    let params = req.get("params").cloned().unwrap_or(Value::Null);
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn parse_command(req: &Value) -> Result<Command, RpcError> {
+    let method = match req.get("method").and_then(|m| m.as_str()) {
+        Some(m) => m,
+        None => return Err(RpcError::new(INVALID_REQUEST, "missing method")),
+    };
+
+    match method {
+        "get_status" => Ok(Command::GetStatus),
+        "get_config" => Ok(Command::GetConfig),
+        "set_profile" => {
+            let p: ProfileParams = params(req)?;
+            Ok(Command::SetProfile(p.name))
+        },
+        "set_curve" => {
+            let p: CurveParams = params(req)?;
+            Ok(Command::SetCurve(p.points))
+        },
+        "set_manual_speed" => {
+            let p: ManualSpeedParams = params(req)?;
+            if p.speed > 100 {
+                return Err(RpcError::new(INVALID_PARAMS, "speed must be <= 100"));
+            }
+            if p.timeout == 0 {
+                return Err(RpcError::new(INVALID_PARAMS, "timeout must be > 0"));
+            }
+            Ok(Command::SetManualSpeed { speed: p.speed, timeout: Duration::from_secs(p.timeout) })
+        },
+        "release_to_auto" => Ok(Command::ReleaseToAuto),
+        "subscribe" => Ok(Command::Subscribe),
+        m => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method \"{}\"", m))),
+    }
+}
+
+/// Forwards `command` to the main loop and waits for its reply
+pub fn dispatch(command: Command, tx: &Sender<Request>) -> Reply {
+    let (reply_tx, reply_rx) = mpsc::channel();
+
+    if tx.send(Request { command, reply: reply_tx }).is_err() {
+        return Err(RpcError::new(UNAVAILABLE, "daemon is shutting down"));
+    }
+
+    match reply_rx.recv_timeout(REPLY_TIMEOUT) {
+        Ok(reply) => reply,
+        Err(_) => Err(RpcError::new(UNAVAILABLE, "daemon did not respond")),
+    }
+}
+
+fn handle_line(line: &str, tx: &Sender<Request>, subs: &Subscribers,
+               access: Access) -> Response {
+    let req: Value = match serde_json::from_str(line) {
+        Ok(v) => v,
+        Err(e) => return Response::new(Value::Null, Err(RpcError::new(PARSE_ERROR, e))),
+    };
+
+    let id = req.get("id").cloned().unwrap_or(Value::Null);
+
+    match parse_command(&req) {
+        Ok(ref command) if access == Access::ReadOnly && !command.is_read_only() =>
+            Response::new(id, Err(RpcError::new(NOT_PERMITTED, "permission denied"))),
+        Ok(Command::Subscribe) => match subs.subscribe() {
+            Ok(s) => {
+                let mut response = Response::new(id, Ok(Value::Bool(true)));
+                response.subscription = Some(s);
+                response
+            },
+            Err(e) => Response::new(id, Err(e)),
+        },
+        Ok(command) => Response::new(id, dispatch(command, tx)),
+        Err(e) => Response::new(id, Err(e)),
+    }
+}
+
+/// Writes every update of `subscription` to `writer` until either side
+/// goes away
+fn stream_updates<W: Write>(subscription: &Subscription, mut writer: W) -> io::Result<()> {
+    while let Some(line) = subscription.next() {
+        if let Err(e) = writer.write_all(line.as_bytes()) {
+            subscription.close();
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn handle_client<R: Read, W: Write>(reader: R, mut writer: W, tx: Sender<Request>,
+                                    subs: &Subscribers, access: Access) -> io::Result<()> {
+    for line in BufReader::new(reader).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = handle_line(&line, &tx, subs, access);
+        let json = format!("{}\n", serde_json::to_string(&response).unwrap());
+        writer.write_all(json.as_bytes())?;
+
+        // Subscribed connections only receive updates from now on
+        if let Some(ref subscription) = response.subscription {
+            return stream_updates(subscription, writer);
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_tcp_client(stream: TcpStream, tx: Sender<Request>, subs: &Subscribers,
+                     access: Access) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(SUBSCRIBER_TIMEOUT))?;
+    handle_client(stream.try_clone()?, stream, tx, subs, access)
+}
+
+/// Accepts connections on `listener` until `running` is cleared. Each
+/// connection is served on its own thread; requests are newline delimited
+/// JSON objects with a `method`, optional `params` and an optional `id` that
+/// is echoed back in the response along with either a `result` or an `error`.
+/// After a `subscribe` request the connection receives a `status`
+/// notification from `subs` after every update instead. The peers of a TCP
+/// connection cannot be told apart, so all of them are granted `access`.
+pub fn serve_tcp(listener: TcpListener, tx: Sender<Request>, subs: Arc<Subscribers>,
+                 running: &'static AtomicBool, access: Access) {
+    match listener.local_addr() {
+        Ok(addr) => info!("Spinning up TCP server at {:?}", addr),
+        Err(_) => info!("Spinning up TCP server")
+    };
+
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("TCP server error: {:?}", e);
+        return;
+    }
+
+    accept_connections(|| listener.accept().map(|(s, _)| s), running, "TCP server", |s| {
+        debug!("Incoming TCP connection: {:?}", s.peer_addr());
+        let tx = tx.clone();
+        let subs = subs.clone();
+        thread::spawn(move || {
+            if let Err(e) = s.set_nonblocking(false).and_then(|_| handle_tcp_client(s, tx, &subs, access)) {
+                debug!("TCP client error: {}", e);
+            }
+        });
+    });
+    debug!("TCP server terminated")
+}
+
+/// Hands every connection returned by `accept` to `handle` until `running`
+/// is cleared. `accept` must not block so that shutting down does not depend
+/// on a last client connecting.
+pub fn accept_connections<S, A, H>(mut accept: A, running: &AtomicBool, name: &str, mut handle: H)
+    where A: FnMut() -> io::Result<S>, H: FnMut(S)
+{
+    while running.load(Ordering::Relaxed) {
+        match accept() {
+            Ok(s) => handle(s),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_INTERVAL);
+            },
+            Err(e) => {
+                error!("{} error: {:?}", name, e);
+                thread::sleep(ACCEPT_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Binds every address `addr` resolves to. Fails only if none of them could
+/// be bound, so that `localhost` works on hosts without IPv6.
+pub fn bind_tcp(addr: &str) -> Result<Vec<TcpListener>, String> {
+    let addrs = addr.to_socket_addrs()
+        .map_err(|e| format!("Invalid listen address \"{}\": {}", addr, e))?;
+
+    let mut listeners = Vec::new();
+    let mut error = None;
+    for a in addrs {
+        match TcpListener::bind(a) {
+            Ok(l) => listeners.push(l),
+            Err(e) => {
+                debug!("Could not listen on {}: {}", a, e);
+                error = Some(format!("Could not listen on {}: {}", a, e));
+            }
+        }
+    }
+
+    if listeners.is_empty() {
+        return Err(error.unwrap_or_else(|| format!("\"{}\" did not resolve to any address", addr)));
+    }
+    Ok(listeners)
+}
+
+/// Ownership, mode and write permissions of the control socket
+#[cfg(unix)]
+#[derive(Clone)]
+pub struct SocketPermissions {
+    pub owner: Option<Uid>,
+    pub group: Option<Gid>,
+    pub mode: u32,
+    /// Users allowed to issue commands that modify the fan state
+    pub allowed_uids: Vec<Uid>,
+    /// Groups allowed to issue commands that modify the fan state
+    pub allowed_gids: Vec<Gid>,
+}
+
+#[cfg(unix)]
+impl SocketPermissions {
+
+    /// Access granted to a peer with the given credentials. root and the
+    /// user running the daemon are always allowed full access.
+    pub fn access(&self, uid: Uid, gid: Gid) -> Access {
+        if uid.is_root() || uid == unistd::geteuid()
+            || self.allowed_uids.contains(&uid) || self.allowed_gids.contains(&gid) {
+            Access::Full
+        } else {
+            Access::ReadOnly
+        }
+    }
+}
+
+/// Default path of the control socket
+#[cfg(unix)]
+pub fn default_socket_path() -> PathBuf {
+    match ::std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("nvfancontrol.sock"),
+        None => PathBuf::from("/run/nvfancontrol.sock"),
+    }
+}
+
+/// Resolves a user name or numeric id
+#[cfg(unix)]
+pub fn parse_user(s: &str) -> Result<Uid, String> {
+    if let Ok(uid) = s.parse::<u32>() {
+        return Ok(Uid::from_raw(uid));
+    }
+    match User::from_name(s) {
+        Ok(Some(u)) => Ok(u.uid),
+        Ok(None) => Err(format!("unknown user \"{}\"", s)),
+        Err(e) => Err(format!("could not look up user \"{}\": {}", s, e)),
+    }
+}
+
+/// Resolves a group name or numeric id
+#[cfg(unix)]
+pub fn parse_group(s: &str) -> Result<Gid, String> {
+    if let Ok(gid) = s.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    match Group::from_name(s) {
+        Ok(Some(g)) => Ok(g.gid),
+        Ok(None) => Err(format!("unknown group \"{}\"", s)),
+        Err(e) => Err(format!("could not look up group \"{}\": {}", s, e)),
+    }
+}
+
+/// Creates the control socket at `path` and applies `perms`. A stale socket
+/// left behind at `path` is removed first.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path, perms: &SocketPermissions) -> Result<UnixListener, String> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(format!("{:?} exists and is not a socket", path));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!("{:?} is in use by another process", path));
+        }
+        fs::remove_file(path).map_err(|e| format!("Could not remove stale socket {:?}: {}", path, e))?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .map_err(|e| format!("Could not bind socket {:?}: {}", path, e))?;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(perms.mode))
+        .map_err(|e| format!("Could not set mode of {:?}: {}", path, e))?;
+
+    if perms.owner.is_some() || perms.group.is_some() {
+        unistd::chown(path, perms.owner, perms.group)
+            .map_err(|e| format!("Could not change owner of {:?}: {}", path, e))?;
+    }
+
+    Ok(listener)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_credentials(stream: &UnixStream) -> Result<(Uid, Gid), String> {
+    use nix::sys::socket::{getsockopt, sockopt};
+
+    match getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials) {
+        Ok(cred) => Ok((Uid::from_raw(cred.uid()), Gid::from_raw(cred.gid()))),
+        Err(e) => Err(format!("SO_PEERCRED failed: {}", e)),
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn peer_credentials(_: &UnixStream) -> Result<(Uid, Gid), String> {
+    Err("peer credentials are not supported on this platform".to_string())
+}
+
+#[cfg(unix)]
+fn handle_unix_client(stream: UnixStream, tx: Sender<Request>, subs: &Subscribers,
+                      perms: &SocketPermissions) -> io::Result<()> {
+    let access = match peer_credentials(&stream) {
+        Ok((uid, gid)) => {
+            let access = perms.access(uid, gid);
+            debug!("Incoming control connection: uid {} gid {}; {:?}", uid, gid, access);
+            access
+        },
+        Err(e) => {
+            warn!("Could not determine peer credentials: {}; read-only access", e);
+            Access::ReadOnly
+        }
+    };
+
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(SUBSCRIBER_TIMEOUT))?;
+    handle_client(stream.try_clone()?, stream, tx, subs, access)
+}
+
+/// Accepts connections on the control socket until `running` is cleared.
+/// The protocol is the same as for `serve_tcp` but commands that modify the
+/// fan state are only accepted from peers allowed by `perms`.
+#[cfg(unix)]
+pub fn serve_unix(listener: UnixListener, tx: Sender<Request>, subs: Arc<Subscribers>,
+                  running: &'static AtomicBool, perms: SocketPermissions) {
+    match listener.local_addr().ok().and_then(|a| a.as_pathname().map(|p| p.to_owned())) {
+        Some(path) => info!("Listening for control connections at {:?}", path),
+        None => info!("Listening for control connections")
+    };
+
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!("Control socket error: {:?}", e);
+        return;
+    }
+
+    let perms = Arc::new(perms);
+
+    accept_connections(|| listener.accept().map(|(s, _)| s), running, "Control socket", |s| {
+        let tx = tx.clone();
+        let subs = subs.clone();
+        let perms = perms.clone();
+        thread::spawn(move || {
+            let served = s.set_nonblocking(false)
+                          .and_then(|_| handle_unix_client(s, tx, &subs, &perms));
+            if let Err(e) = served {
+                debug!("Control client error: {}", e);
+            }
+        });
+    });
+    debug!("Control socket server terminated")
+}
+
+#[test]
+fn test_parse_command() {
+    let parse = |s: &str| parse_command(&serde_json::from_str(s).unwrap());
+
+    assert_eq!(parse(r#"{"method": "get_status"}"#), Ok(Command::GetStatus));
+    assert_eq!(parse(r#"{"method": "set_profile", "params": {"name": "silent"}}"#),
+               Ok(Command::SetProfile("silent".to_string())));
+    assert_eq!(parse(r#"{"method": "set_curve", "params": {"points": [[40, 20], [80, 80]]}}"#),
+               Ok(Command::SetCurve(vec![(40, 20), (80, 80)])));
+    assert_eq!(parse(r#"{"method": "set_manual_speed", "params": {"speed": 60, "timeout": 30}}"#),
+               Ok(Command::SetManualSpeed { speed: 60, timeout: Duration::from_secs(30) }));
+    assert_eq!(parse(r#"{"method": "release_to_auto"}"#), Ok(Command::ReleaseToAuto));
+
+    assert_eq!(parse(r#"{"params": {}}"#).unwrap_err().code, INVALID_REQUEST);
+    assert_eq!(parse(r#"{"method": "reboot"}"#).unwrap_err().code, METHOD_NOT_FOUND);
+    assert_eq!(parse(r#"{"method": "set_profile"}"#).unwrap_err().code, INVALID_PARAMS);
+    assert_eq!(parse(r#"{"method": "set_manual_speed", "params": {"speed": 60}}"#)
+               .unwrap_err().code, INVALID_PARAMS);
+    assert_eq!(parse(r#"{"method": "set_manual_speed", "params": {"speed": 160, "timeout": 5}}"#)
+               .unwrap_err().code, INVALID_PARAMS);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket_access() {
+    static TEST_RUNNING: AtomicBool = AtomicBool::new(true);
+
+    let path = ::std::env::temp_dir().join(format!("nvfancontrol-test-{}.sock",
+                                                  ::std::process::id()));
+    let perms = SocketPermissions {
+        owner: None,
+        group: None,
+        mode: 0o600,
+        allowed_uids: Vec::new(),
+        allowed_gids: Vec::new(),
+    };
+    let listener = bind_unix(&path, &perms).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    // The socket is in use so it must not be replaced
+    assert!(bind_unix(&path, &perms).is_err());
+
+    let (tx, rx) = mpsc::channel::<Request>();
+    thread::spawn(move || serve_unix(listener, tx, Arc::new(Subscribers::new()), &TEST_RUNNING, perms));
+    thread::spawn(move || {
+        for req in rx {
+            req.reply.send(Ok(Value::Null)).unwrap();
+        }
+    });
+
+    // Connecting as the user running the daemon grants full access
+    let stream = UnixStream::connect(&path).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    writer.write_all(b"{\"id\": 1, \"method\": \"release_to_auto\"}\n").unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "{\"id\":1,\"result\":null}\n");
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_socket_permissions() {
+    let perms = SocketPermissions {
+        owner: None,
+        group: None,
+        mode: 0o666,
+        allowed_uids: vec![Uid::from_raw(1000)],
+        allowed_gids: vec![Gid::from_raw(500)],
+    };
+
+    assert_eq!(perms.access(Uid::from_raw(0), Gid::from_raw(0)), Access::Full);
+    assert_eq!(perms.access(Uid::from_raw(1000), Gid::from_raw(100)), Access::Full);
+    assert_eq!(perms.access(Uid::from_raw(1001), Gid::from_raw(500)), Access::Full);
+    if unistd::geteuid() != Uid::from_raw(1001) {
+        assert_eq!(perms.access(Uid::from_raw(1001), Gid::from_raw(100)), Access::ReadOnly);
+    }
+}
+
+#[test]
+fn test_read_only_access() {
+    let (tx, rx) = mpsc::channel::<Request>();
+    thread::spawn(move || {
+        for req in rx {
+            req.reply.send(Ok(Value::Null)).unwrap();
+        }
+    });
+
+    let input = "{\"id\": 1, \"method\": \"get_status\"}\n\
+                 {\"id\": 2, \"method\": \"set_curve\", \"params\": {\"points\": [[40, 20], [80, 80]]}}\n";
+    let mut output = Vec::new();
+    handle_client(input.as_bytes(), &mut output, tx, &Subscribers::new(), Access::ReadOnly).unwrap();
+
+    assert_eq!(String::from_utf8(output).unwrap(),
+               "{\"id\":1,\"result\":null}\n\
+                {\"id\":2,\"error\":{\"code\":-32001,\"message\":\"permission denied\"}}\n");
+}
+
+#[test]
+fn test_protocol_over_loopback() {
+    static TEST_RUNNING: AtomicBool = AtomicBool::new(true);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel::<Request>();
+
+    thread::spawn(move || {
+        serve_tcp(listener, tx, Arc::new(Subscribers::new()), &TEST_RUNNING, Access::Full)
+    });
+
+    // Stands in for the main loop
+    thread::spawn(move || {
+        for req in rx {
+            let reply = match req.command {
+                Command::GetStatus => Ok(serde_json::from_str(r#"{"temp": 50}"#).unwrap()),
+                Command::SetProfile(ref name) if name == "silent" => Ok(Value::Null),
+                Command::SetProfile(name) =>
+                    Err(RpcError::new(INVALID_PARAMS, format!("unknown profile \"{}\"", name))),
+                Command::ReleaseToAuto => Err(RpcError::new(NOT_PERMITTED, "monitor-only")),
+                _ => Ok(Value::Null),
+            };
+            req.reply.send(reply).unwrap();
+        }
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+
+    let mut call = |req: &str| -> Value {
+        writer.write_all(format!("{}\n", req).as_bytes()).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    };
+    let json = |s: &str| -> Value { serde_json::from_str(s).unwrap() };
+
+    assert_eq!(call(r#"{"id": 1, "method": "get_status"}"#),
+               json(r#"{"id": 1, "result": {"temp": 50}}"#));
+    assert_eq!(call(r#"{"id": "a", "method": "set_profile", "params": {"name": "silent"}}"#),
+               json(r#"{"id": "a", "result": null}"#));
+    assert_eq!(call(r#"{"id": 2, "method": "set_profile", "params": {"name": "turbo"}}"#),
+               json(r#"{"id": 2, "error": {"code": -32602, "message": "unknown profile \"turbo\""}}"#));
+    assert_eq!(call(r#"{"id": 3, "method": "release_to_auto"}"#),
+               json(r#"{"id": 3, "error": {"code": -32001, "message": "monitor-only"}}"#));
+    assert_eq!(call(r#"{"method": "fly"}"#),
+               json(r#"{"id": null, "error": {"code": -32601, "message": "unknown method \"fly\""}}"#));
+    assert_eq!(call("not json")["error"]["code"], json("-32700"));
+}
+
+#[test]
+fn test_tcp_read_only() {
+    static TEST_RUNNING: AtomicBool = AtomicBool::new(true);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel::<Request>();
+
+    thread::spawn(move || {
+        serve_tcp(listener, tx, Arc::new(Subscribers::new()), &TEST_RUNNING, Access::ReadOnly)
+    });
+    thread::spawn(move || {
+        for req in rx {
+            req.reply.send(Ok(Value::Null)).unwrap();
+        }
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut call = |req: &str| -> Value {
+        writer.write_all(format!("{}\n", req).as_bytes()).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    };
+
+    assert_eq!(call(r#"{"id": 1, "method": "get_status"}"#)["result"], Value::Null);
+    let reply = call(r#"{"id": 2, "method": "set_manual_speed", "params": {"speed": 100, "timeout": 30}}"#);
+    assert_eq!(reply["error"]["code"], Value::from(NOT_PERMITTED));
+    let reply = call(r#"{"id": 3, "method": "release_to_auto"}"#);
+    assert_eq!(reply["error"]["code"], Value::from(NOT_PERMITTED));
+}
+
+#[test]
+fn test_subscription_coalesces_updates() {
+    let subs = Subscribers::new();
+    let subscription = subs.subscribe().unwrap();
+
+    subs.publish(&1);
+    subs.publish(&2);
+    assert_eq!(*subscription.next().unwrap(), "{\"method\":\"status\",\"params\":2}\n");
+
+    subs.publish(&3);
+    subs.close();
+    assert!(subscription.next().is_none());
+    assert!(subs.is_empty());
+}
+
+#[test]
+fn test_subscribe_over_loopback() {
+    static TEST_RUNNING: AtomicBool = AtomicBool::new(true);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, _rx) = mpsc::channel::<Request>();
+    let subs = Arc::new(Subscribers::new());
+
+    let srv_subs = subs.clone();
+    thread::spawn(move || serve_tcp(listener, tx, srv_subs, &TEST_RUNNING, Access::ReadOnly));
+
+    let mut clients = Vec::new();
+    for id in 0..2 {
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        writer.write_all(format!("{{\"id\": {}, \"method\": \"subscribe\"}}\n", id).as_bytes())
+              .unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, format!("{{\"id\":{},\"result\":true}}\n", id));
+        clients.push(reader);
+    }
+
+    subs.publish(&serde_json::json!({"temp": 50}));
+    for reader in clients.iter_mut() {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"method\":\"status\",\"params\":{\"temp\":50}}\n");
+    }
+
+    // Ending the subscriptions closes the connections
+    drop(clients.pop());
+    subs.close();
+    let mut line = String::new();
+    assert_eq!(clients[0].read_line(&mut line).unwrap(), 0);
+}
+
+#[test]
+fn test_bind_tcp() {
+    let listeners = bind_tcp("127.0.0.1:0").unwrap();
+    assert_eq!(listeners.len(), 1);
+
+    let addr = listeners[0].local_addr().unwrap();
+    let err = bind_tcp(&addr.to_string()).err().unwrap();
+    assert!(err.starts_with(&format!("Could not listen on {}", addr)));
+
+    assert!(bind_tcp("127.0.0.1").err().unwrap().starts_with("Invalid listen address"));
+}
+
+#[test]
+fn test_serve_tcp_stops() {
+    static TEST_RUNNING: AtomicBool = AtomicBool::new(true);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (tx, _rx) = mpsc::channel::<Request>();
+    let server = thread::spawn(move || {
+        serve_tcp(listener, tx, Arc::new(Subscribers::new()), &TEST_RUNNING, Access::ReadOnly)
+    });
+
+    // No client is needed to wake the server up
+    TEST_RUNNING.store(false, Ordering::Relaxed);
+    server.join().unwrap();
+}
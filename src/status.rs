@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+
+use conflict::ConflictKind;
+use health::FanFault;
+use nvctrl::{NvFanController, NVCtrlFanControlState, ThermalThresholds};
+use sample::Sample;
+
+/// Version of the status document; increased on incompatible changes
+pub const STATUS_VERSION: u32 = 1;
+
+/// Status of all GPUs as reported by `-j`, `get_status` and subscriptions
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    pub version: u32,
+    /// Seconds since the epoch of the last update; `-1` before the first one
+    pub timespec: i64,
+    pub gpus: Vec<GPUData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GPUData {
+    pub index: u32,
+    pub name: String,
+    /// Whether the fans of this GPU are driven by nvfancontrol
+    pub controlled: bool,
+    pub temp: i32,
+    /// Degrees left until the GPU slows down; `None` if the slowdown
+    /// threshold is not available
+    pub throttle_distance: Option<i32>,
+    /// Speed requested by the curve; only for the controlled GPU
+    pub target: Option<i32>,
+    pub coolers: Vec<CoolerData>,
+    /// Graphics utilization; `-1` if not available
+    pub load: i32,
+    pub utilization: BTreeMap<String, i32>,
+    pub mode: Option<NVCtrlFanControlState>,
+    /// Active profile; only for the controlled GPU
+    pub profile: Option<String>,
+    pub fanflicker_interventions: u64,
+    pub update_errors: u64,
+    /// Fan control contested by another program; only for the controlled
+    /// GPU
+    pub conflict: Option<ConflictData>,
+    /// Requests made to the driver on the last update, including those for
+    /// the status of every GPU; only for the controlled GPU and if counted
+    pub round_trips: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CoolerData {
+    pub id: u32,
+    pub speed: i32,
+    pub rpm: i32,
+    /// Detected failure of the cooler; only for the controlled GPU
+    pub fault: Option<FanFault>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConflictData {
+    /// What the other program changed
+    pub kind: ConflictKind,
+    /// Coolers whose level was changed; empty for the control mode
+    pub coolers: Vec<u32>,
+    /// Policy applied to the conflict: "yield", "reassert" or "log"
+    pub policy: String,
+    /// Seconds until the fans are taken back; only while yielding
+    pub resume_in: Option<u64>,
+}
+
+impl Status {
+    pub fn new(timespec: i64, gpus: Vec<GPUData>) -> Status {
+        Status { version: STATUS_VERSION, timespec, gpus }
+    }
+
+    /// The GPU whose fans are driven by nvfancontrol, if any
+    pub fn controlled(&self) -> Option<&GPUData> {
+        self.gpus.iter().find(|g| g.controlled)
+    }
+}
+
+impl GPUData {
+    /// Reads the current state of GPU `index`. Fields that only apply to the
+    /// controlled GPU are left empty. Values that cannot be read are reported
+    /// at `-1` or left empty so that one unreadable GPU or cooler does not
+    /// hide the others.
+    pub fn read<C: NvFanController>(ctrl: &C, index: u32) -> GPUData {
+        let coolers = ctrl.gpu_coolers(index).map(|c| c.to_vec()).unwrap_or_default().iter()
+            .map(|id| CoolerData {
+                id: *id,
+                speed: ctrl.get_fanspeed(index, *id).unwrap_or(-1),
+                rpm: ctrl.get_fanspeed_rpm(index, *id).unwrap_or(-1),
+                fault: None,
+            })
+            .collect();
+
+        let utilization: BTreeMap<String, i32> = ctrl.get_utilization(index)
+            .map(|u| u.iter().map(|(k, v)| (k.to_string(), *v)).collect())
+            .unwrap_or_default();
+
+        let temp = ctrl.get_temp(index).ok();
+
+        GPUData {
+            index,
+            name: ctrl.get_adapter(index).unwrap_or_default(),
+            controlled: false,
+            temp: temp.unwrap_or(-1),
+            throttle_distance: temp.and_then(|temp| {
+                ctrl.get_thermal_thresholds(index).ok().map(|t| t.slowdown - temp)
+            }),
+            target: None,
+            coolers,
+            load: utilization.get("graphics").cloned().unwrap_or(-1),
+            utilization,
+            mode: ctrl.get_ctrl_status(index).ok(),
+            profile: None,
+            fanflicker_interventions: 0,
+            update_errors: 0,
+            conflict: None,
+            round_trips: None,
+        }
+    }
+
+    /// The state of GPU `index` as found in `sample`, without querying the
+    /// driver again. Unreadable coolers are reported at `-1`.
+    pub fn from_sample(index: u32, name: String, sample: &Sample,
+                       thresholds: Option<ThermalThresholds>) -> GPUData {
+        GPUData {
+            index,
+            name,
+            controlled: false,
+            temp: sample.temp,
+            throttle_distance: thresholds.map(|t| t.slowdown - sample.temp),
+            target: None,
+            coolers: sample.coolers.iter().map(|c| CoolerData {
+                id: c.id,
+                speed: c.level.unwrap_or(-1),
+                rpm: c.rpm.unwrap_or(-1),
+                fault: None,
+            }).collect(),
+            load: sample.utilization.get("graphics").cloned().unwrap_or(-1),
+            utilization: sample.utilization.clone(),
+            mode: Some(sample.mode),
+            profile: None,
+            fanflicker_interventions: 0,
+            update_errors: 0,
+            conflict: None,
+            round_trips: None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub fn make_test_status() -> Status {
+    let mut utilization = BTreeMap::new();
+    utilization.insert("graphics".to_string(), 42);
+    utilization.insert("memory".to_string(), 7);
+
+    let controlled = GPUData {
+        index: 1,
+        name: "GeForce \"Test\" GPU".to_string(),
+        controlled: true,
+        temp: 55,
+        throttle_distance: Some(33),
+        target: Some(45),
+        coolers: vec![CoolerData { id: 2, speed: 40, rpm: 1200, fault: None },
+                      CoolerData { id: 3, speed: 41, rpm: 1250, fault: Some(FanFault::LowRpm) }],
+        load: 42,
+        utilization,
+        mode: Some(NVCtrlFanControlState::Manual),
+        profile: Some("silent".to_string()),
+        fanflicker_interventions: 3,
+        update_errors: 1,
+        conflict: Some(ConflictData { kind: ConflictKind::Level, coolers: vec![3],
+                                      policy: "yield".to_string(), resume_in: Some(240) }),
+        round_trips: Some(7),
+    };
+
+    let other = GPUData {
+        index: 0,
+        name: "Other GPU".to_string(),
+        controlled: false,
+        temp: 35,
+        throttle_distance: None,
+        target: None,
+        coolers: vec![CoolerData { id: 0, speed: 0, rpm: 0, fault: None }],
+        load: -1,
+        utilization: BTreeMap::new(),
+        mode: Some(NVCtrlFanControlState::Auto),
+        profile: None,
+        fanflicker_interventions: 0,
+        update_errors: 0,
+        conflict: None,
+        round_trips: None,
+    };
+
+    Status::new(1000, vec![other, controlled])
+}
+
+#[test]
+fn test_status_round_trip() {
+    let status = make_test_status();
+    let json = ::serde_json::to_string(&status).unwrap();
+    let value: ::serde_json::Value = ::serde_json::from_str(&json).unwrap();
+
+    assert_eq!(value["version"], STATUS_VERSION);
+    assert_eq!(value["gpus"][1]["coolers"][0]["rpm"], 1200);
+    assert_eq!(value["gpus"][1]["coolers"][1]["fault"], "low_rpm");
+    assert_eq!(value["gpus"][1]["mode"], "Manual");
+    assert_eq!(value["gpus"][1]["conflict"]["kind"], "level");
+    assert_eq!(value["gpus"][0]["conflict"], ::serde_json::Value::Null);
+    assert_eq!(::serde_json::from_str::<Status>(&json).unwrap(), status);
+    assert_eq!(status.controlled().unwrap().index, 1);
+}
+
+#[test]
+fn test_status_from_sample() {
+    use fake::{FakeController, FakeGpu};
+
+    let ctrl = FakeController::new(FakeGpu::new(62, NVCtrlFanControlState::Manual,
+                                                vec![(0, 40), (1, 45)]));
+    let sample = Sample::read(&ctrl, 0).unwrap();
+    let thresholds = ctrl.get_thermal_thresholds(0).ok();
+    let mut read = GPUData::read(&ctrl, 0);
+    read.name = "Sampled GPU".to_string();
+
+    assert_eq!(GPUData::from_sample(0, "Sampled GPU".to_string(), &sample, thresholds), read);
+}
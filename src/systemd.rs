@@ -0,0 +1,264 @@
+use std::env;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{getsockname, getsockopt, sockopt, SockAddr, SockType};
+
+/// First file descriptor passed by socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Sends state changes to the service manager following the `sd_notify`
+/// protocol
+pub struct Notifier {
+    socket: UnixDatagram,
+    path: PathBuf,
+    watchdog: Option<Duration>,
+    last_ping: Option<Instant>,
+    status: String,
+}
+
+/// A listening socket received through socket activation
+pub enum Activated {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Parses `WATCHDOG_USEC`; the watchdog only applies to this process if
+/// `WATCHDOG_PID` is unset or matches `pid`
+fn watchdog_interval(usec: Option<&str>, watchdog_pid: Option<&str>, pid: u32)
+    -> Result<Option<Duration>, String>
+{
+    let usec = match usec {
+        Some(u) => u.parse::<u64>().map_err(|e| format!("invalid WATCHDOG_USEC \"{}\": {}", u, e))?,
+        None => return Ok(None),
+    };
+
+    match watchdog_pid.map(|p| p.parse::<u32>()) {
+        Some(Ok(p)) if p != pid => Ok(None),
+        Some(Err(e)) => Err(format!("invalid WATCHDOG_PID: {}", e)),
+        _ if usec == 0 => Ok(None),
+        _ => Ok(Some(Duration::from_micros(usec))),
+    }
+}
+
+/// Number of sockets passed to process `pid` according to `LISTEN_PID` and
+/// `LISTEN_FDS`
+fn listen_fd_count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32)
+    -> Result<usize, String>
+{
+    match (listen_pid, listen_fds) {
+        (Some(p), Some(n)) => {
+            let p = p.parse::<u32>().map_err(|e| format!("invalid LISTEN_PID \"{}\": {}", p, e))?;
+            if p != pid {
+                return Ok(0);
+            }
+            n.parse::<usize>().map_err(|e| format!("invalid LISTEN_FDS \"{}\": {}", n, e))
+        },
+        _ => Ok(0),
+    }
+}
+
+impl Notifier {
+
+    /// Connects to the socket given in `NOTIFY_SOCKET`, if any. The variables
+    /// of the protocol are removed from the environment so that hooks and
+    /// other child processes do not inherit them.
+    pub fn from_env() -> Result<Option<Notifier>, String> {
+        let path = env::var_os("NOTIFY_SOCKET");
+        let watchdog = watchdog_interval(env::var("WATCHDOG_USEC").ok().as_deref(),
+                                         env::var("WATCHDOG_PID").ok().as_deref(),
+                                         process::id());
+
+        for var in &["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            env::remove_var(var);
+        }
+
+        match path {
+            Some(p) => Notifier::new(PathBuf::from(p), watchdog?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Creates a notifier for the socket at `path`; a leading `@` denotes an
+    /// abstract socket
+    pub fn new(path: PathBuf, watchdog: Option<Duration>) -> Result<Notifier, String> {
+        let socket = UnixDatagram::unbound()
+            .map_err(|e| format!("Could not create notification socket: {}", e))?;
+        Ok(Notifier { socket, path, watchdog, last_ping: None, status: String::new() })
+    }
+
+    /// Sends `state`, one or more newline separated `KEY=VALUE` assignments
+    pub fn notify(&self, state: &str) -> Result<(), String> {
+        let sent = match self.path.to_str().and_then(|p| p.strip_prefix('@')) {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Some(name) => {
+                #[cfg(target_os = "android")] use std::os::android::net::SocketAddrExt;
+                #[cfg(target_os = "linux")] use std::os::linux::net::SocketAddrExt;
+                use std::os::unix::net::SocketAddr;
+
+                SocketAddr::from_abstract_name(name)
+                    .and_then(|addr| self.socket.send_to_addr(state.as_bytes(), &addr))
+            },
+            _ => self.socket.send_to(state.as_bytes(), &self.path),
+        };
+        sent.map(|_| ()).map_err(|e| format!("Could not notify {:?}: {}", self.path, e))
+    }
+
+    pub fn ready(&self) -> Result<(), String> {
+        self.notify("READY=1")
+    }
+
+    pub fn stopping(&self) -> Result<(), String> {
+        self.notify("STOPPING=1")
+    }
+
+    /// Sends `status` as the status text of the service unless it is
+    /// unchanged
+    pub fn status(&mut self, status: &str) -> Result<(), String> {
+        if status == self.status {
+            return Ok(());
+        }
+        self.notify(&format!("STATUS={}", status))?;
+        self.status = status.to_string();
+        Ok(())
+    }
+
+    /// Interval after which the service manager considers the service hung
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Pings the watchdog if it is enabled and half of its interval has
+    /// passed since the last ping. Returns whether a ping was sent.
+    pub fn watchdog(&mut self, now: Instant) -> Result<bool, String> {
+        let interval = match self.watchdog {
+            Some(i) => i,
+            None => return Ok(false),
+        };
+        if self.last_ping.is_some_and(|t| now.duration_since(t) < interval / 2) {
+            return Ok(false);
+        }
+        self.notify("WATCHDOG=1")?;
+        self.last_ping = Some(now);
+        Ok(true)
+    }
+}
+
+/// Turns an inherited file descriptor into a listener.
+///
+/// # Safety
+///
+/// `fd` must be open and not owned by anything else in this process.
+unsafe fn listener_from_fd(fd: RawFd) -> Result<Activated, String> {
+    let err = |e: ::nix::Error| format!("file descriptor {}: {}", fd, e);
+
+    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(err)?;
+
+    if getsockopt(fd, sockopt::SockType).map_err(err)? != SockType::Stream
+        || !getsockopt(fd, sockopt::AcceptConn).map_err(err)? {
+        return Err(format!("file descriptor {} is not a listening stream socket", fd));
+    }
+
+    match getsockname(fd).map_err(err)? {
+        SockAddr::Inet(_) => Ok(Activated::Tcp(TcpListener::from_raw_fd(fd))),
+        SockAddr::Unix(_) => Ok(Activated::Unix(UnixListener::from_raw_fd(fd))),
+        _ => Err(format!("file descriptor {} is neither a TCP nor a Unix socket", fd)),
+    }
+}
+
+/// Takes the listening sockets passed by socket activation (`LISTEN_FDS`).
+/// The variables of the protocol are removed from the environment.
+pub fn listen_fds() -> Result<Vec<Activated>, String> {
+    let count = listen_fd_count(env::var("LISTEN_PID").ok().as_deref(),
+                                env::var("LISTEN_FDS").ok().as_deref(),
+                                process::id());
+
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    let count = count? as RawFd;
+
+    // The service manager hands these descriptors over to this process and
+    // nothing else refers to them
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| unsafe { listener_from_fd(fd) })
+        .collect()
+}
+
+#[test]
+fn test_parse_environment() {
+    assert_eq!(watchdog_interval(Some("30000000"), None, 42), Ok(Some(Duration::from_secs(30))));
+    assert_eq!(watchdog_interval(Some("500000"), Some("42"), 42),
+               Ok(Some(Duration::from_millis(500))));
+    assert_eq!(watchdog_interval(Some("500000"), Some("7"), 42), Ok(None));
+    assert_eq!(watchdog_interval(Some("0"), None, 42), Ok(None));
+    assert_eq!(watchdog_interval(None, Some("42"), 42), Ok(None));
+    assert!(watchdog_interval(Some("soon"), None, 42).is_err());
+
+    assert_eq!(listen_fd_count(Some("42"), Some("2"), 42), Ok(2));
+    assert_eq!(listen_fd_count(Some("7"), Some("2"), 42), Ok(0));
+    assert_eq!(listen_fd_count(None, Some("2"), 42), Ok(0));
+    assert!(listen_fd_count(Some("42"), Some("x"), 42).is_err());
+}
+
+#[test]
+fn test_notifier() {
+    let dir = env::temp_dir().join(format!("nvfancontrol-notify-{}", process::id()));
+    let _ = ::std::fs::remove_dir_all(&dir);
+    ::std::fs::create_dir(&dir).unwrap();
+    let path = dir.join("notify");
+
+    let manager = UnixDatagram::bind(&path).unwrap();
+    manager.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let recv = || {
+        let mut buf = [0u8; 256];
+        let n = manager.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    };
+
+    let mut notifier = Notifier::new(path, Some(Duration::from_secs(10))).unwrap();
+    let t0 = Instant::now();
+
+    notifier.ready().unwrap();
+    assert_eq!(recv(), "READY=1");
+
+    notifier.status("55°C, 40%").unwrap();
+    notifier.status("55°C, 40%").unwrap();
+    notifier.status("56°C, 41%").unwrap();
+    assert_eq!(recv(), "STATUS=55°C, 40%");
+    assert_eq!(recv(), "STATUS=56°C, 41%");
+
+    assert_eq!(notifier.watchdog(t0), Ok(true));
+    assert_eq!(notifier.watchdog(t0 + Duration::from_secs(4)), Ok(false));
+    assert_eq!(notifier.watchdog(t0 + Duration::from_secs(5)), Ok(true));
+    assert_eq!(recv(), "WATCHDOG=1");
+    assert_eq!(recv(), "WATCHDOG=1");
+
+    let _ = ::std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_listener_from_fd() {
+    use std::os::unix::io::IntoRawFd;
+
+    let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = tcp.local_addr().unwrap();
+    match unsafe { listener_from_fd(tcp.into_raw_fd()) } {
+        Ok(Activated::Tcp(l)) => assert_eq!(l.local_addr().unwrap(), addr),
+        _ => panic!("expected a TCP listener"),
+    }
+
+    let path = env::temp_dir().join(format!("nvfancontrol-activated-{}.sock", process::id()));
+    let _ = ::std::fs::remove_file(&path);
+    let unix = UnixListener::bind(&path).unwrap();
+    assert!(matches!(unsafe { listener_from_fd(unix.into_raw_fd()) }, Ok(Activated::Unix(_))));
+    let _ = ::std::fs::remove_file(&path);
+
+    let datagram = UnixDatagram::unbound().unwrap();
+    assert!(unsafe { listener_from_fd(datagram.into_raw_fd()) }.is_err());
+}
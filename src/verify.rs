@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+
+/// Difference (%) between the requested and the reported level that is
+/// tolerated
+const TOLERANCE: i32 = 2;
+/// Consecutive updates with the same deviation before the level is written
+/// again
+const RETRY_TICKS: u32 = 3;
+/// Lowest upper limit that is learned; a lower reported level more likely
+/// means a broken reading than a fan that cannot go faster
+const MIN_UPPER_LIMIT: i32 = 50;
+
+/// Outcome of comparing the reported level of a cooler to the requested one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// The cooler runs at the requested level or nothing was requested
+    Applied,
+    /// The level deviates but not for long enough to act on it
+    Pending,
+    /// The level keeps deviating and should be written again; `first` for
+    /// the first retry of this deviation
+    Retry { requested: i32, reported: i32, first: bool },
+    /// The cooler does not go beyond the reported level; requests are
+    /// clipped to the new range from now on
+    Limited { requested: i32, reported: i32, range: (i32, i32) },
+}
+
+struct CoolerLevel {
+    requested: Option<i32>,
+    /// Lowest and highest level the cooler is known to achieve
+    range: (i32, i32),
+    /// Level reported while deviating and for how many updates
+    deviation: Option<(i32, u32)>,
+    /// Whether the cooler ran at the requested level when last checked
+    settled: bool,
+    /// Whether the requested level has to be written again
+    rewrite: bool,
+    /// Whether the level was changed by someone else and is left to them
+    overridden: bool,
+}
+
+/// Verifies that the coolers run at the levels written to them. Drivers may
+/// accept a level and silently clamp or ignore it; if a cooler reports the
+/// same deviating level for several updates the level is written again, and
+/// if that does not help either the reported level becomes a limit of the
+/// cooler that later requests are clipped to.
+#[derive(Default)]
+pub struct LevelVerifier {
+    coolers: BTreeMap<u32, CoolerLevel>,
+}
+
+impl CoolerLevel {
+    fn new() -> CoolerLevel {
+        CoolerLevel { requested: None, range: (0, 100), deviation: None, settled: false,
+                      rewrite: false, overridden: false }
+    }
+}
+
+impl LevelVerifier {
+
+    pub fn new() -> LevelVerifier {
+        LevelVerifier::default()
+    }
+
+    /// `level` clipped to the range cooler `id` is known to achieve
+    pub fn clip(&self, id: u32, level: i32) -> i32 {
+        match self.coolers.get(&id) {
+            Some(c) => level.max(c.range.0).min(c.range.1),
+            None => level,
+        }
+    }
+
+    /// Records that `level` was written to cooler `id`
+    pub fn set(&mut self, id: u32, level: i32) {
+        let cooler = self.coolers.entry(id).or_insert_with(CoolerLevel::new);
+        if cooler.requested != Some(level) {
+            cooler.requested = Some(level);
+            cooler.settled = false;
+        }
+        cooler.rewrite = false;
+        cooler.overridden = false;
+    }
+
+    /// Leaves the level someone else set on cooler `id` alone; it is neither
+    /// written again nor learned as a limit until a new level is written
+    pub fn leave(&mut self, id: u32) {
+        if let Some(c) = self.coolers.get_mut(&id) {
+            c.overridden = c.requested.is_some();
+            c.rewrite = false;
+            c.deviation = None;
+        }
+    }
+
+    /// Whether `level` has to be written to cooler `id` as it was not
+    /// requested before or the requested level is to be written again
+    pub fn needs_write(&self, id: u32, level: i32) -> bool {
+        match self.coolers.get(&id) {
+            Some(c) => c.requested != Some(level) || c.rewrite,
+            None => true,
+        }
+    }
+
+    /// Has the requested level of cooler `id` written again on the next
+    /// update, e.g. after someone else changed it
+    pub fn reassert(&mut self, id: u32) {
+        if let Some(c) = self.coolers.get_mut(&id) {
+            c.rewrite = c.requested.is_some();
+        }
+    }
+
+    /// Forgets the requested levels once the coolers are handed back to auto
+    pub fn clear(&mut self) {
+        for c in self.coolers.values_mut() {
+            c.requested = None;
+            c.deviation = None;
+            c.settled = false;
+            c.rewrite = false;
+            c.overridden = false;
+        }
+    }
+
+    /// The level last written to cooler `id`, unless handed back to auto
+    pub fn requested(&self, id: u32) -> Option<i32> {
+        self.coolers.get(&id).and_then(|c| c.requested)
+    }
+
+    /// Whether cooler `id` ran at the requested level when last checked but
+    /// now reports a different one. Unlike a level that never took effect
+    /// this means it was changed by someone else.
+    pub fn changed(&self, id: u32, reported: i32) -> bool {
+        match self.coolers.get(&id) {
+            Some(&CoolerLevel { requested: Some(r), settled: true, .. }) =>
+                (reported - r).abs() > TOLERANCE,
+            _ => false,
+        }
+    }
+
+    /// Compares the level reported by cooler `id` to the requested one
+    pub fn check(&mut self, id: u32, reported: i32) -> Verdict {
+        let cooler = match self.coolers.get_mut(&id) {
+            Some(c) => c,
+            None => return Verdict::Applied,
+        };
+        let requested = match cooler.requested {
+            Some(r) => r,
+            None => return Verdict::Applied,
+        };
+
+        let deviates = (reported - requested).abs() > TOLERANCE;
+        if cooler.overridden && deviates {
+            return Verdict::Applied;
+        }
+        cooler.overridden = false;
+
+        cooler.settled = !deviates;
+        if cooler.settled {
+            cooler.deviation = None;
+            return Verdict::Applied;
+        }
+
+        // A fan that is still speeding up or slowing down changes its level
+        let count = match cooler.deviation {
+            Some((level, n)) if (level - reported).abs() <= TOLERANCE => n + 1,
+            _ => 1,
+        };
+        cooler.deviation = Some((reported, count));
+
+        let learnable = reported > requested || reported >= MIN_UPPER_LIMIT;
+        if count >= 2 * RETRY_TICKS && learnable {
+            if reported > requested {
+                cooler.range.0 = reported;
+            } else {
+                cooler.range.1 = reported;
+            }
+            cooler.deviation = None;
+            Verdict::Limited { requested, reported, range: cooler.range }
+        } else if count % RETRY_TICKS == 0 {
+            cooler.rewrite = true;
+            Verdict::Retry { requested, reported, first: count == RETRY_TICKS }
+        } else {
+            Verdict::Pending
+        }
+    }
+}
+
+#[test]
+fn test_level_clamped_by_driver() {
+    let mut levels = LevelVerifier::new();
+    assert_eq!(levels.check(0, 30), Verdict::Applied);
+
+    levels.set(0, 20);
+    assert_eq!(levels.check(0, 21), Verdict::Applied);
+
+    // The driver does not go below 30%
+    let verdicts: Vec<Verdict> = (0..6).map(|_| levels.check(0, 30)).collect();
+    assert_eq!(verdicts, vec![
+        Verdict::Pending, Verdict::Pending,
+        Verdict::Retry { requested: 20, reported: 30, first: true },
+        Verdict::Pending, Verdict::Pending,
+        Verdict::Limited { requested: 20, reported: 30, range: (30, 100) },
+    ]);
+    assert_eq!(levels.clip(0, 20), 30);
+    assert_eq!(levels.clip(0, 60), 60);
+    // Other coolers are not affected
+    assert_eq!(levels.clip(1, 20), 20);
+
+    levels.set(0, 30);
+    assert_eq!(levels.check(0, 30), Verdict::Applied);
+    levels.clear();
+    assert_eq!(levels.requested(0), None);
+    assert_eq!(levels.check(0, 45), Verdict::Applied);
+}
+
+#[test]
+fn test_level_deviation_not_learned() {
+    let mut levels = LevelVerifier::new();
+    levels.set(0, 80);
+
+    // A fan that is still speeding up is not limited
+    for &reported in [40, 50, 60, 70, 75, 76].iter() {
+        assert_eq!(levels.check(0, reported), Verdict::Pending);
+    }
+
+    // Neither is one that reports an implausibly low level
+    let verdicts: Vec<Verdict> = (0..9).map(|_| levels.check(0, 10)).collect();
+    assert_eq!(verdicts[2], Verdict::Retry { requested: 80, reported: 10, first: true });
+    assert_eq!(verdicts[5], Verdict::Retry { requested: 80, reported: 10, first: false });
+    assert_eq!(verdicts[8], Verdict::Retry { requested: 80, reported: 10, first: false });
+    assert_eq!(levels.clip(0, 80), 80);
+    assert!(!levels.changed(0, 10));
+}
+
+#[test]
+fn test_level_changed_by_others() {
+    let mut levels = LevelVerifier::new();
+    levels.set(0, 40);
+    // Not applied yet
+    assert!(!levels.changed(0, 70));
+
+    assert_eq!(levels.check(0, 40), Verdict::Applied);
+    assert!(!levels.changed(0, 41));
+    assert!(levels.changed(0, 70));
+
+    // A new request has to be applied first
+    levels.set(0, 40);
+    assert!(levels.changed(0, 70));
+    levels.set(0, 60);
+    assert!(!levels.changed(0, 70));
+    levels.clear();
+    assert!(!levels.changed(0, 70));
+}
+
+#[test]
+fn test_level_left_to_others() {
+    let mut levels = LevelVerifier::new();
+    levels.set(0, 40);
+    assert_eq!(levels.check(0, 40), Verdict::Applied);
+
+    // Neither retried nor learned while left to someone else
+    levels.leave(0);
+    for _ in 0..9 {
+        assert_eq!(levels.check(0, 70), Verdict::Applied);
+        assert!(levels.changed(0, 70));
+        assert!(!levels.needs_write(0, 40));
+    }
+    assert_eq!(levels.clip(0, 40), 40);
+
+    // Until a new level is requested
+    assert!(levels.needs_write(0, 50));
+    levels.set(0, 50);
+    assert_eq!(levels.check(0, 70), Verdict::Pending);
+}
+
+#[test]
+fn test_level_needs_write() {
+    let mut levels = LevelVerifier::new();
+    assert!(levels.needs_write(0, 40));
+
+    levels.set(0, 40);
+    assert!(!levels.needs_write(0, 40));
+    assert!(levels.needs_write(0, 50));
+
+    // Only written again once the deviation persists
+    assert_eq!(levels.check(0, 70), Verdict::Pending);
+    assert!(!levels.needs_write(0, 40));
+    assert_eq!(levels.check(0, 70), Verdict::Pending);
+    assert_eq!(levels.check(0, 70), Verdict::Retry { requested: 40, reported: 70, first: true });
+    assert!(levels.needs_write(0, 40));
+    levels.set(0, 40);
+    assert!(!levels.needs_write(0, 40));
+
+    levels.reassert(0);
+    assert!(levels.needs_write(0, 40));
+    levels.clear();
+    levels.reassert(0);
+    assert!(levels.needs_write(0, 40));
+}